@@ -0,0 +1,283 @@
+//! The block directory layout used by the CLI: a `raw` block file, its spent UTXOs file and the
+//! Zstandard-compressed versions of both (`raw.zst` and `spent_utxos.zst`).
+
+use crate::error::ProcessError;
+use crate::fetcher::SpentUtxoFetcher;
+use crate::format::{load_utxo_data, OutputFormat, BINARY_MAGIC};
+use crate::validation::{
+    check_coinbase_claim, check_input_locks, check_outpoints, compute_fees, TxFee,
+};
+use crate::UtxoData;
+use bitcoin::consensus::deserialize;
+use bitcoin::Block;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A block directory to process, with its optional expected hash.
+pub struct BlockJob {
+    pub dir: PathBuf,
+    pub expected_hash: Option<String>,
+}
+
+/// Checks that the block has the expected hash.
+pub fn check_block_hash(block: &Block, expected_hash: &str) -> Result<(), ProcessError> {
+    let actual_hash = block.block_hash();
+
+    if actual_hash.to_string() != expected_hash {
+        return Err(ProcessError::HashMismatch {
+            expected: expected_hash.to_string(),
+            actual: actual_hash,
+        });
+    }
+    Ok(())
+}
+
+/// Returns the raw block in the directory, decompressing "raw.zst" if there is no "raw" file.
+pub fn read_block(dir: &Path) -> Result<Block, ProcessError> {
+    let raw_file = dir.join("raw");
+    let raw_zst = dir.join("raw.zst");
+
+    let raw_bytes = if !raw_file.exists() && raw_zst.exists() {
+        zstd::stream::decode_all(File::open(&raw_zst)?)?
+    } else {
+        std::fs::read(&raw_file)?
+    };
+    Ok(deserialize(&raw_bytes)?)
+}
+
+/// Returns the spent UTXOs file in the directory, preferring the uncompressed versions.
+pub fn find_spent_utxos_file(dir: &Path) -> Option<PathBuf> {
+    [
+        OutputFormat::Json.file_name(),
+        OutputFormat::Binary.file_name(),
+        "spent_utxos.zst",
+    ]
+    .into_iter()
+    .map(|name| dir.join(name))
+    .find(|path| path.exists())
+}
+
+/// Parses the batch manifest, where each line has a block directory and an optional hash.
+pub fn parse_manifest(manifest: &Path) -> Result<Vec<BlockJob>, ProcessError> {
+    let content = std::fs::read_to_string(manifest)?;
+    let base_dir = manifest.parent().unwrap_or(Path::new(""));
+
+    let mut jobs = Vec::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let dir = fields.next().expect("Line is not empty");
+        let expected_hash = fields.next().map(ToString::to_string);
+        if fields.next().is_some() {
+            return Err(ProcessError::Manifest(line.to_string()));
+        }
+
+        jobs.push(BlockJob {
+            dir: base_dir.join(dir),
+            expected_hash,
+        });
+    }
+    Ok(jobs)
+}
+
+/// Computes the fee of each transaction and checks that the coinbase doesn't claim more than the
+/// subsidy plus fees, that no immature coinbase output is spent and that all the timelocks are
+/// satisfied. Returns the fees if the fetched data is consistent with the block.
+pub async fn check_spent_utxos(
+    fetcher: &SpentUtxoFetcher,
+    block: &Block,
+    utxos: &[UtxoData],
+) -> Result<Vec<TxFee>, ProcessError> {
+    let height = fetcher.fetch_block_height(block).await?;
+
+    let fees = compute_fees(block, utxos)?;
+    check_coinbase_claim(block, height, &fees, fetcher.params())?;
+
+    // The median time past of the previous block, used by BIP 113 and time-based BIP 68 locks
+    let mtp = fetcher.coin_time(block.block_hash()).await?;
+    let violations = check_input_locks(block, height, mtp, utxos, fetcher.params());
+    if !violations.is_empty() {
+        return Err(ProcessError::Invalid(violations));
+    }
+
+    Ok(fees)
+}
+
+/// Checks the existing spent UTXOs file of a block directory against the block. Returns the
+/// checked file and the fees.
+pub async fn verify_block_dir(
+    fetcher: &SpentUtxoFetcher,
+    dir: &Path,
+    block_hash: Option<&str>,
+) -> Result<(PathBuf, Vec<TxFee>), ProcessError> {
+    let block = read_block(dir)?;
+    if let Some(expected_hash) = block_hash {
+        check_block_hash(&block, expected_hash)?;
+    }
+
+    let utxos_file = find_spent_utxos_file(dir)
+        .ok_or_else(|| ProcessError::MissingUtxoFile(dir.to_path_buf()))?;
+    let utxo_file = load_utxo_data(&utxos_file)?;
+
+    // If the file includes outpoints, they must be the ones the block inputs reference
+    if let Some(keyed) = utxo_file.keyed() {
+        check_outpoints(&block, keyed)?;
+    }
+
+    let spent: Vec<UtxoData> = utxo_file
+        .entries()
+        .into_iter()
+        .map(|(_, utxo)| utxo.clone())
+        .collect();
+    let fees = check_spent_utxos(fetcher, &block, &spent).await?;
+    Ok((utxos_file, fees))
+}
+
+/// Compresses the raw block and the spent UTXOs files of the directory, without overwriting
+/// existing compressed files.
+pub fn compress_block_dir(dir: &Path) -> Result<(), ProcessError> {
+    let raw_file = dir.join("raw");
+    let raw_zst = dir.join("raw.zst");
+    let spent_utxos_zst = dir.join("spent_utxos.zst");
+    let spent_utxos_file = [OutputFormat::Json, OutputFormat::Binary]
+        .into_iter()
+        .map(|format| dir.join(format.file_name()))
+        .find(|path| path.exists())
+        .ok_or_else(|| ProcessError::MissingUtxoFile(dir.to_path_buf()))?;
+
+    if raw_zst.exists() || spent_utxos_zst.exists() {
+        return Err(ProcessError::OutputExists(dir.to_path_buf()));
+    }
+    // Compress the raw block file.
+    compress_file(&raw_file, &raw_zst)?;
+    // Compress the spent UTXOs file.
+    compress_file(&spent_utxos_file, &spent_utxos_zst)?;
+    Ok(())
+}
+
+/// Restores the raw block and spent UTXOs files from their compressed versions, without
+/// overwriting existing files. Returns the paths of the written files.
+pub fn decompress_block_dir(dir: &Path) -> Result<Vec<PathBuf>, ProcessError> {
+    let raw_bytes = zstd::stream::decode_all(File::open(dir.join("raw.zst"))?)?;
+    let utxo_bytes = zstd::stream::decode_all(File::open(dir.join("spent_utxos.zst"))?)?;
+
+    // The compressed UTXOs may be either JSON or binary, which we tell apart by the magic bytes
+    let format = if utxo_bytes.starts_with(BINARY_MAGIC) {
+        OutputFormat::Binary
+    } else {
+        OutputFormat::Json
+    };
+    let outputs = [
+        (dir.join("raw"), raw_bytes),
+        (dir.join(format.file_name()), utxo_bytes),
+    ];
+
+    if outputs.iter().any(|(path, _)| path.exists()) {
+        return Err(ProcessError::OutputExists(dir.to_path_buf()));
+    }
+    let mut written = Vec::new();
+    for (path, bytes) in outputs {
+        std::fs::write(&path, bytes)?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// Compresses the input file into the output file, with the maximum Zstandard level.
+pub fn compress_file(input_path: &Path, output_path: &Path) -> io::Result<()> {
+    let raw_bytes = std::fs::read(input_path)?;
+
+    // Compress the data with a compression level (1 is fast, 22 is maximum compression)
+    let compressed_data = zstd::encode_all(&raw_bytes[..], 22)?;
+
+    // Write the compressed data to a file
+    let mut compressed_file = File::create(output_path)?;
+    compressed_file.write_all(&compressed_data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a new empty directory for the test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("block_dir_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let dir = temp_dir("manifest");
+        let manifest = dir.join("manifest.txt");
+        let hash = "00000000000000000000000000000000000000000000000000000000000000ff";
+        std::fs::write(
+            &manifest,
+            format!("# Blocks to fetch\n\n840000 {hash}\n   \n  841000  \n  # Skipped\n"),
+        )
+        .unwrap();
+
+        // The directories are relative to the manifest
+        let jobs = parse_manifest(&manifest).unwrap();
+        let jobs: Vec<_> = jobs
+            .iter()
+            .map(|job| (job.dir.clone(), job.expected_hash.as_deref()))
+            .collect();
+        assert_eq!(
+            jobs,
+            [(dir.join("840000"), Some(hash)), (dir.join("841000"), None)]
+        );
+
+        std::fs::write(&manifest, format!("840000\n841000 {hash} extra\n")).unwrap();
+        assert!(matches!(
+            parse_manifest(&manifest),
+            Err(ProcessError::Manifest(line)) if line == format!("841000 {hash} extra")
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            parse_manifest(&manifest),
+            Err(ProcessError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_compression_roundtrip() {
+        for format in [OutputFormat::Json, OutputFormat::Binary] {
+            let dir = temp_dir(format.file_name());
+            let raw: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+            let utxos = match format {
+                OutputFormat::Binary => [&BINARY_MAGIC[..], &[0; 100]].concat(),
+                _ => br#"[{"txout":{"value":1,"script_pubkey":"51"}}]"#.to_vec(),
+            };
+            std::fs::write(dir.join("raw"), &raw).unwrap();
+            std::fs::write(dir.join(format.file_name()), &utxos).unwrap();
+
+            compress_block_dir(&dir).unwrap();
+            // Nothing is overwritten
+            assert!(matches!(
+                compress_block_dir(&dir),
+                Err(ProcessError::OutputExists(_))
+            ));
+            assert!(matches!(
+                decompress_block_dir(&dir),
+                Err(ProcessError::OutputExists(_))
+            ));
+
+            std::fs::remove_file(dir.join("raw")).unwrap();
+            std::fs::remove_file(dir.join(format.file_name())).unwrap();
+            let written = decompress_block_dir(&dir).unwrap();
+            assert_eq!(written, [dir.join("raw"), dir.join(format.file_name())]);
+            assert_eq!(std::fs::read(dir.join("raw")).unwrap(), raw);
+            assert_eq!(std::fs::read(dir.join(format.file_name())).unwrap(), utxos);
+
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
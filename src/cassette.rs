@@ -0,0 +1,171 @@
+//! Cassettes with the responses of the chain APIs, which make runs reproducible: a run in record
+//! mode writes every response it gets to the cassette, and a run in replay mode makes no request,
+//! serving every response from the cassette instead.
+//!
+//! A cassette file has one JSON object per line, with the requested `url` and the response body
+//! as `text`, or as `hex` if it is binary. Only successful responses are recorded. The JSON-RPC
+//! calls to Bitcoin Core all go to the same URL, so their `url` is followed by the method and the
+//! parameters (e.g. `http://127.0.0.1:8332/ getblock ["<hash>",3]`).
+
+use bitcoin::hex::{DisplayHex, FromHex};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// A recorded response.
+pub struct Interaction {
+    pub url: String,
+    #[serde(flatten)]
+    pub body: Body,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+/// A response body, as text if it is valid UTF-8 and as hex otherwise (e.g. raw blocks).
+pub enum Body {
+    Text(String),
+    Hex(String),
+}
+
+impl Body {
+    fn new(bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Body::Text(text.to_string()),
+            Err(_) => Body::Hex(bytes.to_lower_hex_string()),
+        }
+    }
+
+    fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        match self {
+            Body::Text(text) => Ok(text.as_bytes().to_vec()),
+            Body::Hex(hex) => {
+                Vec::from_hex(hex).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+        }
+    }
+}
+
+/// The cassette used by a [crate::SpentUtxoFetcher], see [crate::SpentUtxoFetcher::with_cassette].
+pub enum Cassette {
+    /// Every response is appended to the file as soon as it is received, so the responses of
+    /// failed runs are kept too.
+    Record(Mutex<File>),
+    /// Responses are served by URL.
+    Replay(HashMap<String, Vec<u8>>),
+}
+
+impl Cassette {
+    /// Creates (or truncates) the cassette file and records into it.
+    pub fn record(path: &Path) -> io::Result<Self> {
+        Ok(Cassette::Record(Mutex::new(File::create(path)?)))
+    }
+
+    /// Reads the cassette file to replay its responses.
+    pub fn replay(path: &Path) -> io::Result<Self> {
+        let mut interactions = Vec::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                interactions.push(serde_json::from_str(&line)?);
+            }
+        }
+        Self::from_interactions(interactions)
+    }
+
+    /// Replays the given responses. If a URL is repeated, the last response is used.
+    pub fn from_interactions(
+        interactions: impl IntoIterator<Item = Interaction>,
+    ) -> io::Result<Self> {
+        let responses = interactions
+            .into_iter()
+            .map(|interaction| Ok((interaction.url, interaction.body.to_bytes()?)))
+            .collect::<io::Result<_>>()?;
+        Ok(Cassette::Replay(responses))
+    }
+
+    /// Returns the response to replay for `url`, or `None` when recording. The inner `None`
+    /// means that the URL is not in the cassette.
+    pub(crate) fn replayed(&self, url: &str) -> Option<Option<Vec<u8>>> {
+        match self {
+            Cassette::Record(_) => None,
+            Cassette::Replay(responses) => Some(responses.get(url).cloned()),
+        }
+    }
+
+    /// Appends the response to the cassette file, when recording.
+    pub(crate) fn record_response(&self, url: &str, body: &[u8]) -> io::Result<()> {
+        if let Cassette::Record(file) = self {
+            let interaction = Interaction {
+                url: url.to_string(),
+                body: Body::new(body),
+            };
+            let line = serde_json::to_string(&interaction)?;
+            writeln!(file.lock().unwrap(), "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetcher::SpentUtxoFetcher;
+    use crate::mock::MockEsplora;
+    use crate::FetchError;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{OutPoint, Txid};
+    use wiremock::ResponseTemplate;
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let mock = MockEsplora::start(&[1_000, 2_000, 3_000]).await;
+        let coinbase =
+            bitcoin::constants::genesis_block(bitcoin::Network::Bitcoin).txdata[0].clone();
+        mock.add_transaction(&coinbase, Some(2)).await;
+        let raw_block = vec![0xff, 0x00, 0xfe];
+        let raw_path = format!("/block/{}/raw", mock.block_hash(1));
+        mock.mount(
+            &raw_path,
+            ResponseTemplate::new(200).set_body_bytes(raw_block.clone()),
+        )
+        .await;
+
+        let path = std::env::temp_dir().join(format!("cassette_{}.jsonl", std::process::id()));
+        let fetcher = mock
+            .fetcher()
+            .with_cassette(Cassette::record(&path).unwrap());
+        let outpoint = OutPoint::new(coinbase.compute_txid(), 0);
+        let utxo = fetcher.fetch_utxo(outpoint).await.unwrap();
+        // Not a block, but binary data must be recorded as is
+        assert!(fetcher
+            .fetch_block_by_hash(mock.block_hash(1))
+            .await
+            .is_err());
+
+        // The replaying fetcher makes no request, even to the same URLs
+        let requests = mock.request_count().await;
+        let url = mock.server.uri();
+        let fetcher = SpentUtxoFetcher::new()
+            .with_esplora_url(&url)
+            .with_cassette(Cassette::replay(&path).unwrap());
+        assert_eq!(fetcher.fetch_utxo(outpoint).await.unwrap(), utxo);
+        assert_eq!(mock.request_count().await, requests);
+
+        let Cassette::Replay(responses) = Cassette::replay(&path).unwrap() else {
+            panic!("Expected a replay cassette");
+        };
+        assert_eq!(responses[&format!("{}{}", url, raw_path)], raw_block);
+
+        // Anything else is not in the cassette
+        let other = OutPoint::new(Txid::all_zeros(), 0);
+        assert!(matches!(
+            fetcher.fetch_utxo(other).await,
+            Err(FetchError::NotRecorded(_))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+}
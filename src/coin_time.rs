@@ -1,135 +1,179 @@
 use crate::error::FetchError;
-use crate::request_from_url;
-use crate::END;
-use crate::GREEN;
+use crate::fetcher::SpentUtxoFetcher;
+use bitcoin::block::{Header, Version};
+use bitcoin::hashes::Hash;
+use bitcoin::{BlockHash, CompactTarget, TxMerkleNode};
+use serde::{Deserialize, Serialize};
+use std::io;
 
-/// Fetches the “coin time” for a UTXO by computing the median-time-past (MTP) of the 11 blocks
-/// immediately preceding the current block (i.e. from height h–11 to h–1).
+/// Fetches the “coin time” for the UTXOs confirmed in the block with `block_hash`, by computing
+/// the median-time-past (MTP) of the 11 blocks immediately preceding it (i.e. from height h–11 to
+/// h–1).
 ///
 /// In Bitcoin’s consensus rules (BIP 68), the creation time (mining date) of an output is defined
-/// as the MTP of the block immediately before the block that mined it. Here we fetch all 11
+/// as the MTP of the block immediately before the block that mined it. Here we get all 11
 /// timestamps and then compute the median (middle element when the timestamps are sorted).
+///
+/// The ancestors are found by following `prev_blockhash` from the confirming block, rather than
+/// by asking for whatever block is at each height, so the result belongs to the chain of that
+/// block even if the backend reorganized. If the backend's block at one of the heights is not the
+/// ancestor we follow, [FetchError::ChainMismatch] is returned.
+///
+/// As in Bitcoin Core, blocks with fewer than 11 ancestors take the median of the ones they have,
+/// and outputs created in the genesis block get the MTP of the genesis block itself.
+///
+/// Headers are cached in the `fetcher`, so no request is made if all of them are already known.
 pub async fn fetch_coin_time(
-    client: &reqwest::Client,
-    current_height: u32,
+    fetcher: &SpentUtxoFetcher,
+    block_hash: BlockHash,
 ) -> Result<u32, FetchError> {
-    println!(
-        "Fetching timestamps for heights: ({}..={})",
-        color_last_3_digits(current_height - 11),
-        color_last_3_digits(current_height - 1),
-    );
-
-    // Run tasks concurrently:
-    // - Fetch 10 blocks from (current_height - 11) to (current_height - 2).
-    // - Fetch the block at (current_height - 1).
-    let (mut timestamps, last_block_timestamp) = futures::try_join!(
-        fetch_batch_timestamps(client, current_height - 2),
-        fetch_last_block_timestamp(client, current_height - 1)
-    )?;
-
-    // Get the vector with the previous 11 timestamps, sort it, and get the median value.
-    assert_eq!(timestamps.len(), 10);
-    timestamps.push(last_block_timestamp);
-    timestamps.sort();
+    let blocks = fetch_coin_time_blocks(fetcher, block_hash).await?;
+    Ok(median_time_past(
+        blocks.iter().map(|block| block.time).collect(),
+    ))
+}
 
-    print_timestamps(&timestamps);
-    // For 11 timestamps, the median is at index 5.
-    let median_time = timestamps[5];
-    Ok(median_time)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// A block whose timestamp is part of a median time past.
+pub struct TimestampedBlock {
+    pub height: u32,
+    pub block_hash: BlockHash,
+    pub time: u32,
 }
 
-/// Fetches the timestamps for the previous 10 blocks, including the `top_height` block.
-/// It uses the endpoint: GET https://blockstream.info/api/blocks/{top_height}
-async fn fetch_batch_timestamps(
-    client: &reqwest::Client,
-    top_height: u32,
-) -> Result<Vec<u32>, FetchError> {
-    let blocks_url = format!("https://blockstream.info/api/blocks/{}", top_height);
-    let response = request_from_url(client, &blocks_url)
-        .await
-        .map_err(FetchError::CoinTime)?;
-    let blocks: Vec<serde_json::Value> = serde_json::from_str(&response)?;
-
-    // Extract timestamps from each block.
-    let timestamps = blocks
-        .into_iter()
-        .enumerate()
-        .map(|(i, block)| {
-            let height = block["height"].as_u64().unwrap() as u32;
-            assert_eq!(top_height - i as u32, height); // Ensure we are reading the previous blocks
-
-            block["timestamp"]
-                .as_u64()
-                .expect("Timestamp missing in block") as u32
-        })
-        .collect();
-    Ok(timestamps)
+/// Returns the blocks whose timestamps define the coin time of the UTXOs confirmed in the block
+/// with `block_hash`, from the highest one: its parent and up to 10 of its ancestors, or the
+/// genesis block itself for the genesis block (see [fetch_coin_time]).
+pub async fn fetch_coin_time_blocks(
+    fetcher: &SpentUtxoFetcher,
+    block_hash: BlockHash,
+) -> Result<Vec<TimestampedBlock>, FetchError> {
+    let (height, header) = fetcher.block_header(block_hash).await?;
+    // The genesis block is the only one that is its own MTP block
+    match height {
+        0 => fetch_mtp_blocks(fetcher, block_hash, 0).await,
+        _ => fetch_mtp_blocks(fetcher, header.prev_blockhash, height - 1).await,
+    }
 }
 
-/// Fetches the timestamp of a single block given its height using two endpoints:
-/// 1. GET https://blockstream.info/api/block-height/{height} returns the block hash.
-/// 2. GET https://blockstream.info/api/block/{hash} returns the block details as JSON.
-async fn fetch_last_block_timestamp(
-    client: &reqwest::Client,
+/// Returns the blocks whose timestamps define the BIP 113 median time past of the block with
+/// `block_hash` at `height`: the block itself and up to 10 of its ancestors, from the highest one.
+///
+/// Every block must be the backend's block at its height, or [FetchError::ChainMismatch] is
+/// returned.
+pub async fn fetch_mtp_blocks(
+    fetcher: &SpentUtxoFetcher,
+    block_hash: BlockHash,
     height: u32,
-) -> Result<u32, FetchError> {
-    let block_height_url = format!("https://blockstream.info/api/block-height/{}", height);
-    let hash_response = request_from_url(client, &block_height_url)
-        .await
-        .map_err(FetchError::CoinTime)?;
-    let block_hash = hash_response.trim();
-
-    let block_url = format!("https://blockstream.info/api/block/{}", block_hash);
-    let block_response = request_from_url(client, &block_url)
-        .await
-        .map_err(FetchError::CoinTime)?;
-    let block: serde_json::Value = serde_json::from_str(&block_response)?;
-
-    let timestamp = block["timestamp"]
-        .as_u64()
-        .expect("Timestamp missing in block") as u32;
-    Ok(timestamp)
+) -> Result<Vec<TimestampedBlock>, FetchError> {
+    let mut blocks = Vec::with_capacity(11);
+    let mut next_hash = block_hash;
+    for height in (height.saturating_sub(10)..=height).rev() {
+        let header = ancestor_header(fetcher, next_hash, height).await?;
+        blocks.push(TimestampedBlock {
+            height,
+            block_hash: next_hash,
+            time: header.time,
+        });
+        next_hash = header.prev_blockhash;
+    }
+    Ok(blocks)
 }
 
-fn print_timestamps(timestamps: &[u32]) {
-    assert_eq!(timestamps.len(), 11);
-    // The median of 11 elements is at index 5.
-    let median_index = 5;
-
-    print!("Median Time: [");
-    for (i, ts) in timestamps.iter().enumerate() {
-        // Check if we are at the middle element.
-        if i == median_index {
-            // Print the middle element in green.
-            print!("{GREEN}{ts}{END}");
-        } else {
-            print!("{ts}");
-        }
+/// Returns the median of the (up to 11) block timestamps, which is the element at index `n / 2`
+/// once sorted, as Bitcoin Core's `GetMedianTimePast`.
+pub fn median_time_past(mut timestamps: Vec<u32>) -> u32 {
+    timestamps.sort();
+    // For 11 timestamps, the median is at index 5.
+    timestamps[timestamps.len() / 2]
+}
 
-        // Print a comma separator for all elements except the last.
-        if i < 11 - 1 {
-            print!(", ");
+/// Returns the header of the block with `block_hash`, which must be the backend's block at
+/// `height`. Missing headers are fetched along with their 9 ancestors.
+async fn ancestor_header(
+    fetcher: &SpentUtxoFetcher,
+    block_hash: BlockHash,
+    height: u32,
+) -> Result<Header, FetchError> {
+    if let Some(found) = fetcher.cached_block_hash(height) {
+        if found != block_hash {
+            return Err(FetchError::ChainMismatch {
+                height,
+                expected: block_hash,
+                found,
+            });
         }
     }
-    println!("]");
+    if let Some((_, header)) = fetcher.cached_header(&block_hash) {
+        return Ok(header);
+    }
+
+    fetch_header_batch(fetcher, height).await?;
+    match fetcher.cached_header(&block_hash) {
+        Some((_, header)) => Ok(header),
+        None => Err(FetchError::ChainMismatch {
+            height,
+            expected: block_hash,
+            found: fetcher.block_hash(height).await?,
+        }),
+    }
+}
+
+/// Fetches and caches the headers of the block at `top_height` and its 9 previous blocks (or down
+/// to the genesis block) in the backend's best chain, along with their hashes by height.
+/// It uses the Esplora endpoint: GET /blocks/{top_height}
+async fn fetch_header_batch(fetcher: &SpentUtxoFetcher, top_height: u32) -> Result<(), FetchError> {
+    let blocks_url = format!("{}/blocks/{}", fetcher.esplora_url(), top_height);
+    let response = fetcher
+        .request_from_url(&blocks_url, FetchError::CoinTime)
+        .await?;
+    let blocks: Vec<EsploraBlock> = serde_json::from_str(&response)?;
+
+    for block in blocks {
+        let (height, header) = block.into_header()?;
+        fetcher.cache_header(height, header);
+        fetcher.cache_block_hash(height, header.block_hash());
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+/// A block as returned by the Esplora API, of which we only read the header fields.
+pub(crate) struct EsploraBlock {
+    id: BlockHash,
+    height: u32,
+    version: i32,
+    timestamp: u32,
+    bits: u32,
+    nonce: u32,
+    merkle_root: TxMerkleNode,
+    /// Missing for the genesis block
+    previousblockhash: Option<BlockHash>,
 }
 
-fn color_last_3_digits(num: u32) -> String {
-    let num_str = num.to_string();
-    let len = num_str.len();
-    if len > 3 {
-        let (prefix, suffix) = num_str.split_at(len - 3);
-        // Concatenate the prefix (uncolored) with the colored suffix, then reset the color.
-        format!("{prefix}{GREEN}{suffix}{END}")
-    } else {
-        // If the number has three or fewer digits, color the entire string.
-        format!("{GREEN}{num_str}{END}")
+impl EsploraBlock {
+    /// Returns the height and header of the block, checking that the header hashes to its id.
+    pub(crate) fn into_header(self) -> Result<(u32, Header), FetchError> {
+        let header = Header {
+            version: Version::from_consensus(self.version),
+            prev_blockhash: self.previousblockhash.unwrap_or(BlockHash::all_zeros()),
+            merkle_root: self.merkle_root,
+            time: self.timestamp,
+            bits: CompactTarget::from_consensus(self.bits),
+            nonce: self.nonce,
+        };
+        if header.block_hash() != self.id {
+            let message = format!("Header fields don't hash to block {}", self.id);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message).into());
+        }
+        Ok((self.height, header))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mock::MockEsplora;
     use chrono::DateTime;
 
     /// Validate that a timestamp refers to the expected UTC date and return the unix value.
@@ -144,9 +188,13 @@ mod tests {
         unix_timestamp
     }
 
-    async fn assert_coin_time(client: &reqwest::Client, height: u32, expected_coin_time: u32) {
-        // Call fetch_coin_time, which makes real HTTP requests.
-        match fetch_coin_time(client, height).await {
+    async fn assert_coin_time(fetcher: &SpentUtxoFetcher, height: u32, expected_coin_time: u32) {
+        // Call fetch_coin_time, which makes real HTTP requests for the uncached blocks.
+        let coin_time = match fetcher.block_hash(height).await {
+            Ok(block_hash) => fetch_coin_time(fetcher, block_hash).await,
+            Err(e) => Err(e),
+        };
+        match coin_time {
             Ok(coin_time) => {
                 assert_eq!(
                     coin_time, expected_coin_time,
@@ -161,15 +209,16 @@ mod tests {
     }
 
     #[tokio::test]
+    #[ignore = "makes requests to blockstream.info, run with --ignored"]
     async fn test_fetch_coin_time() {
-        let client = reqwest::Client::new();
+        let fetcher = SpentUtxoFetcher::new();
 
         let height = 866_339;
         // You can verify that blocks 866,328 to 866,338 have ascending timestamps, and the block
         // at the middle (i.e. block 866,333) has this exact timestamp. This is the median of the
         // previous 11 blocks, which is the coin time for block 866,339.
         let expected_coin_time = assert_date(1_729_331_091, "2024-10-19 09:44:51");
-        assert_coin_time(&client, height, expected_coin_time).await;
+        assert_coin_time(&fetcher, height, expected_coin_time).await;
 
         let height = 156_119;
         // From blocks 156,108 to 156,118 the middle block would be 156,113. However, this block
@@ -178,14 +227,115 @@ mod tests {
         //
         // Timestamp order: 113 > 118 > 117 > 116 > 115 > [114] > 112 > 111 > 110 > 109 > 108
         let expected_coin_time = assert_date(1_323_065_878, "2011-12-05 06:17:58");
-        assert_coin_time(&client, height, expected_coin_time).await;
+        assert_coin_time(&fetcher, height, expected_coin_time).await;
 
         // Try with a height that is one less, effectively moving the median block to 156,112
         let expected_coin_time = assert_date(1_323_065_825, "2011-12-05 06:17:05");
-        assert_coin_time(&client, height - 1, expected_coin_time).await;
+        assert_coin_time(&fetcher, height - 1, expected_coin_time).await;
 
         // By adding one, we shift the median block to 156,115
         let expected_coin_time = assert_date(1_323_066_065, "2011-12-05 06:21:05");
-        assert_coin_time(&client, height + 1, expected_coin_time).await;
+        assert_coin_time(&fetcher, height + 1, expected_coin_time).await;
+    }
+
+    #[tokio::test]
+    async fn test_mock_coin_time() {
+        // Blocks every 10 minutes, except block 6, mined two hours later than it should
+        let base = 1_600_000_000;
+        let timestamps: Vec<u32> = (0..14)
+            .map(|height| base + 600 * height + if height == 6 { 7_200 } else { 0 })
+            .collect();
+        let mock = MockEsplora::start(&timestamps).await;
+        let fetcher = mock.fetcher();
+
+        // For block 12 the MTP block is 11, and from blocks 1 to 11 the middle one would be 6.
+        // However, it has the highest timestamp, so the median is that of block 7 instead.
+        assert_coin_time(&fetcher, 12, base + 600 * 7).await;
+        // One block less moves the median block to 5, and one more to 8
+        assert_coin_time(&fetcher, 11, base + 600 * 5).await;
+        assert_coin_time(&fetcher, 13, base + 600 * 8).await;
+
+        // Everything is cached by now, so the same coin times need no request
+        let requests = mock.request_count().await;
+        for height in 11..14 {
+            fetcher.coin_time(mock.block_hash(height)).await.unwrap();
+        }
+        assert_eq!(mock.request_count().await, requests);
+    }
+
+    #[tokio::test]
+    async fn test_low_height_coin_time() {
+        // Timestamps of the first mainnet blocks, whose headers are cached so no request is made
+        let genesis = assert_date(1_231_006_505, "2009-01-03 18:15:05");
+        let timestamps = [
+            genesis,
+            1_231_469_665,
+            1_231_469_744,
+            1_231_470_173,
+            1_231_470_988,
+        ];
+        let fetcher = SpentUtxoFetcher::new();
+        cache_chain(&fetcher, &timestamps);
+
+        // Outputs of the genesis block and block 1 both use the genesis MTP
+        assert_coin_time(&fetcher, 0, genesis).await;
+        assert_coin_time(&fetcher, 1, genesis).await;
+        // With an even count the median is the higher of the two middle timestamps
+        assert_coin_time(&fetcher, 2, timestamps[1]).await;
+        assert_coin_time(&fetcher, 3, timestamps[1]).await;
+        assert_coin_time(&fetcher, 4, timestamps[2]).await;
+
+        // The MTP of block 3 is the coin time of block 4, from the same blocks
+        let hashes = cache_chain(&fetcher, &timestamps);
+        let blocks = fetch_mtp_blocks(&fetcher, hashes[3], 3).await.unwrap();
+        let heights: Vec<u32> = blocks.iter().map(|block| block.height).collect();
+        assert_eq!(heights, [3, 2, 1, 0]);
+        assert_eq!(blocks[1].block_hash, hashes[2]);
+        assert_eq!(
+            fetch_coin_time_blocks(&fetcher, hashes[4]).await.unwrap(),
+            blocks
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chain_mismatch() {
+        // The backend has another block at height 1, so blocks 2 and 3 are not in its chain
+        let fetcher = SpentUtxoFetcher::new();
+        let other = BlockHash::from_byte_array([1; 32]);
+        fetcher.cache_block_hash(1, other);
+        let hashes = cache_chain(&fetcher, &[1_000, 2_000, 3_000, 4_000]);
+
+        match fetch_coin_time(&fetcher, hashes[3]).await {
+            Err(FetchError::ChainMismatch {
+                height,
+                expected,
+                found,
+            }) => assert_eq!((height, expected, found), (1, hashes[1], other)),
+            result => panic!("Expected a chain mismatch, got {:?}", result),
+        }
+        assert!(fetch_coin_time(&fetcher, hashes[2]).await.is_err());
+
+        // The coin time of block 1 only depends on the genesis block
+        assert_eq!(fetch_coin_time(&fetcher, hashes[1]).await.unwrap(), 1_000);
+    }
+
+    /// Caches a chain of headers with the given timestamps, starting from height 0, as if it was
+    /// the backend's best chain. Returns the block hashes.
+    fn cache_chain(fetcher: &SpentUtxoFetcher, timestamps: &[u32]) -> Vec<BlockHash> {
+        let mut hashes: Vec<BlockHash> = Vec::new();
+        for (height, time) in timestamps.iter().enumerate() {
+            let header = Header {
+                version: Version::ONE,
+                prev_blockhash: hashes.last().copied().unwrap_or(BlockHash::all_zeros()),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: *time,
+                bits: CompactTarget::from_consensus(0x207f_ffff),
+                nonce: 0,
+            };
+            fetcher.cache_header(height as u32, header);
+            fetcher.cache_block_hash(height as u32, header.block_hash());
+            hashes.push(header.block_hash());
+        }
+        hashes
     }
 }
@@ -0,0 +1,290 @@
+use crate::format::UtxoFile;
+use crate::UtxoData;
+use bitcoin::OutPoint;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+/// A field whose value differs between the two compared UTXOs.
+pub struct FieldDiff {
+    /// The differing field: outpoint, value, script_pubkey, is_coinbase, creation_height or
+    /// creation_time.
+    pub field: &'static str,
+    /// The value in the current file.
+    pub current: String,
+    /// The value in the file we compare against.
+    pub other: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+/// A single difference between the two compared UTXO files.
+pub enum EntryDiff {
+    /// Both files have an entry at this position (or for this outpoint), but some fields differ.
+    Changed {
+        /// The index of the entry in the current file.
+        index: usize,
+        /// The outpoint of the entry, if the files include outpoints.
+        outpoint: Option<OutPoint>,
+        fields: Vec<FieldDiff>,
+    },
+    /// The entry is only in the current file.
+    OnlyInCurrent {
+        index: usize,
+        outpoint: Option<OutPoint>,
+    },
+    /// The entry is only in the file we compare against.
+    OnlyInOther {
+        index: usize,
+        outpoint: Option<OutPoint>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+/// Structured report of the differences between two UTXO files.
+pub struct UtxoDiff {
+    /// Whether the entries were matched by outpoint instead of by position.
+    pub unordered: bool,
+    /// Number of entries in the current file.
+    pub current_len: usize,
+    /// Number of entries in the file we compare against.
+    pub other_len: usize,
+    pub entries: Vec<EntryDiff>,
+}
+
+impl UtxoDiff {
+    /// Whether the two files are equal.
+    pub fn is_empty(&self) -> bool {
+        self.current_len == self.other_len && self.entries.is_empty()
+    }
+}
+
+/// Returns the differing fields of two UTXOs.
+fn diff_fields(current: &UtxoData, other: &UtxoData) -> Vec<FieldDiff> {
+    let mut fields = Vec::new();
+    let mut push = |field, current: String, other: String| {
+        if current != other {
+            fields.push(FieldDiff {
+                field,
+                current,
+                other,
+            });
+        }
+    };
+
+    push(
+        "value",
+        current.txout.value.to_sat().to_string(),
+        other.txout.value.to_sat().to_string(),
+    );
+    push(
+        "script_pubkey",
+        current.txout.script_pubkey.to_hex_string(),
+        other.txout.script_pubkey.to_hex_string(),
+    );
+    push(
+        "is_coinbase",
+        current.is_coinbase.to_string(),
+        other.is_coinbase.to_string(),
+    );
+    push(
+        "creation_height",
+        current.creation_height.to_string(),
+        other.creation_height.to_string(),
+    );
+    push(
+        "creation_time",
+        current.creation_time.to_string(),
+        other.creation_time.to_string(),
+    );
+    fields
+}
+
+/// Compares two UTXO files entry by entry, in file order.
+///
+/// If both files include outpoints, these are compared too.
+pub fn diff_ordered(current: &UtxoFile, other: &UtxoFile) -> UtxoDiff {
+    let current_entries = current.entries();
+    let other_entries = other.entries();
+    let mut entries = Vec::new();
+
+    for (index, (current_entry, other_entry)) in
+        current_entries.iter().zip(&other_entries).enumerate()
+    {
+        let (current_outpoint, current_utxo) = current_entry;
+        let (other_outpoint, other_utxo) = other_entry;
+
+        let mut fields = Vec::new();
+        if let (Some(current_outpoint), Some(other_outpoint)) = (current_outpoint, other_outpoint) {
+            if current_outpoint != other_outpoint {
+                fields.push(FieldDiff {
+                    field: "outpoint",
+                    current: current_outpoint.to_string(),
+                    other: other_outpoint.to_string(),
+                });
+            }
+        }
+        fields.extend(diff_fields(current_utxo, other_utxo));
+
+        if !fields.is_empty() {
+            entries.push(EntryDiff::Changed {
+                index,
+                outpoint: current_outpoint.or(*other_outpoint),
+                fields,
+            });
+        }
+    }
+
+    // Report the entries beyond the length of the shortest file
+    let common = current_entries.len().min(other_entries.len());
+    for (index, (outpoint, _)) in current_entries.iter().enumerate().skip(common) {
+        entries.push(EntryDiff::OnlyInCurrent {
+            index,
+            outpoint: *outpoint,
+        });
+    }
+    for (index, (outpoint, _)) in other_entries.iter().enumerate().skip(common) {
+        entries.push(EntryDiff::OnlyInOther {
+            index,
+            outpoint: *outpoint,
+        });
+    }
+
+    UtxoDiff {
+        unordered: false,
+        current_len: current_entries.len(),
+        other_len: other_entries.len(),
+        entries,
+    }
+}
+
+/// Compares two UTXO files matching their entries by outpoint, regardless of the order.
+///
+/// Returns `None` if any of the files doesn't include outpoints.
+pub fn diff_unordered(current: &UtxoFile, other: &UtxoFile) -> Option<UtxoDiff> {
+    let current_keyed = current.keyed()?;
+    let other_keyed = other.keyed()?;
+
+    let other_map: HashMap<OutPoint, &UtxoData> = other_keyed
+        .0
+        .iter()
+        .map(|(outpoint, utxo)| (*outpoint, utxo))
+        .collect();
+    let mut entries = Vec::new();
+
+    for (index, (outpoint, current_utxo)) in current_keyed.0.iter().enumerate() {
+        match other_map.get(outpoint) {
+            Some(other_utxo) => {
+                let fields = diff_fields(current_utxo, other_utxo);
+                if !fields.is_empty() {
+                    entries.push(EntryDiff::Changed {
+                        index,
+                        outpoint: Some(*outpoint),
+                        fields,
+                    });
+                }
+            }
+            None => entries.push(EntryDiff::OnlyInCurrent {
+                index,
+                outpoint: Some(*outpoint),
+            }),
+        }
+    }
+
+    let current_outpoints: HashSet<&OutPoint> = current_keyed
+        .0
+        .iter()
+        .map(|(outpoint, _)| outpoint)
+        .collect();
+    for (index, (outpoint, _)) in other_keyed.0.iter().enumerate() {
+        if !current_outpoints.contains(outpoint) {
+            entries.push(EntryDiff::OnlyInOther {
+                index,
+                outpoint: Some(*outpoint),
+            });
+        }
+    }
+
+    Some(UtxoDiff {
+        unordered: true,
+        current_len: current_keyed.0.len(),
+        other_len: other_keyed.0.len(),
+        entries,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::KeyedUtxos;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Amount, ScriptBuf, TxOut, Txid};
+
+    fn entry(vout: u32) -> (OutPoint, UtxoData) {
+        let utxo = UtxoData {
+            txout: TxOut {
+                value: Amount::from_sat(1_000),
+                script_pubkey: ScriptBuf::new(),
+            },
+            is_coinbase: false,
+            creation_height: 800_000 + vout,
+            creation_time: 1_690_000_000,
+        };
+        (OutPoint::new(Txid::all_zeros(), vout), utxo)
+    }
+
+    #[test]
+    fn test_diff() {
+        let current: Vec<_> = (0..3).map(entry).collect();
+        let mut other = current.clone();
+        other.swap(0, 1);
+        other[2].1.txout.value = Amount::from_sat(2_000);
+        other.push(entry(3));
+
+        let current = UtxoFile::Keyed(KeyedUtxos(current));
+        let other = UtxoFile::Keyed(KeyedUtxos(other));
+
+        let diff = diff_unordered(&current, &other).unwrap();
+        assert_eq!(
+            diff.entries,
+            [
+                EntryDiff::Changed {
+                    index: 2,
+                    outpoint: Some(entry(2).0),
+                    fields: vec![FieldDiff {
+                        field: "value",
+                        current: "1000".to_string(),
+                        other: "2000".to_string(),
+                    }],
+                },
+                EntryDiff::OnlyInOther {
+                    index: 3,
+                    outpoint: Some(entry(3).0),
+                },
+            ]
+        );
+
+        // By position, the swapped entries differ in their outpoint and height
+        let diff = diff_ordered(&current, &other);
+        assert!(!diff.is_empty());
+        assert_eq!(diff.entries.len(), 4);
+        match &diff.entries[0] {
+            EntryDiff::Changed { fields, .. } => {
+                let names: Vec<_> = fields.iter().map(|field| field.field).collect();
+                assert_eq!(names, ["outpoint", "creation_height"]);
+            }
+            _ => panic!("expected a changed entry"),
+        }
+
+        assert!(diff_ordered(&current, &current).is_empty());
+        let list = UtxoFile::List(
+            current
+                .entries()
+                .into_iter()
+                .map(|(_, utxo)| utxo.clone())
+                .collect(),
+        );
+        assert!(diff_unordered(&current, &list).is_none());
+        assert!(diff_ordered(&current, &list).is_empty());
+    }
+}
@@ -0,0 +1,247 @@
+use bitcoin::opcodes::all::OP_RETURN;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::{Script, ScriptBuf};
+use std::io::{self, Read};
+
+/// Number of special script types of Bitcoin Core's script compression, whose sizes are offset by
+/// this amount for generic scripts.
+const SPECIAL_SCRIPTS: u64 = 6;
+
+/// Bitcoin Core's `MAX_SCRIPT_SIZE`, the maximum size of a spendable script.
+const MAX_SCRIPT_SIZE: usize = 10_000;
+
+/// Appends `n` with Bitcoin Core's `VARINT` encoding (the MSB base-128 format used in the UTXO
+/// database and undo files), where each byte but the last has the high bit set and an offset of
+/// one is subtracted per continuation byte, so that every value has a single encoding.
+pub fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    let mut tmp = [0u8; 10];
+    let mut len = 0;
+    loop {
+        tmp[len] = (n & 0x7F) as u8 | if len > 0 { 0x80 } else { 0x00 };
+        if n <= 0x7F {
+            break;
+        }
+        n = (n >> 7) - 1;
+        len += 1;
+    }
+    // The bytes were computed from least to most significant
+    buf.extend(tmp[..=len].iter().rev());
+}
+
+/// Reads a Bitcoin Core `VARINT` from `reader` (e.g. a byte slice, which is advanced).
+pub fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut n: u64 = 0;
+    loop {
+        let mut byte = [0u8];
+        reader
+            .read_exact(&mut byte)
+            .map_err(|_| io::Error::new(io::ErrorKind::UnexpectedEof, "truncated VARINT"))?;
+        let byte = byte[0];
+
+        if n > (u64::MAX >> 7) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "VARINT is too large",
+            ));
+        }
+        n = (n << 7) | (byte & 0x7F) as u64;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+        n = n
+            .checked_add(1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "VARINT is too large"))?;
+    }
+}
+
+/// Compresses an amount in satoshis as Bitcoin Core's `CompressAmount`, which shortens values
+/// with trailing decimal zeros.
+pub fn compress_amount(mut n: u64) -> u64 {
+    if n == 0 {
+        return 0;
+    }
+    let mut e = 0;
+    while n.is_multiple_of(10) && e < 9 {
+        n /= 10;
+        e += 1;
+    }
+    if e < 9 {
+        let d = n % 10;
+        n /= 10;
+        1 + (n * 9 + d - 1) * 10 + e
+    } else {
+        1 + (n - 1) * 10 + 9
+    }
+}
+
+/// Reverses [compress_amount], as Bitcoin Core's `DecompressAmount`.
+pub fn decompress_amount(mut x: u64) -> u64 {
+    if x == 0 {
+        return 0;
+    }
+    x -= 1;
+    let mut e = x % 10;
+    x /= 10;
+    let mut n = if e < 9 {
+        let d = (x % 9) + 1;
+        x /= 9;
+        x * 10 + d
+    } else {
+        x + 1
+    };
+    while e > 0 {
+        n *= 10;
+        e -= 1;
+    }
+    n
+}
+
+/// Appends the script with Bitcoin Core's `ScriptCompression`: P2PKH, P2SH and P2PK scripts are
+/// written as a type byte and 20 or 32 bytes, and any other script as its size plus
+/// [SPECIAL_SCRIPTS] (a `VARINT`) followed by the script bytes.
+pub fn write_compressed_script(buf: &mut Vec<u8>, script: &Script) {
+    let bytes = script.as_bytes();
+    if script.is_p2pkh() {
+        buf.push(0x00);
+        buf.extend_from_slice(&bytes[3..23]);
+    } else if script.is_p2sh() {
+        buf.push(0x01);
+        buf.extend_from_slice(&bytes[2..22]);
+    } else if bytes.len() == 35 && bytes[0] == 33 && bytes[34] == 0xAC && bytes[1] & 0xFE == 0x02 {
+        // P2PK with a compressed key: the type is the key prefix, followed by the x coordinate
+        buf.extend_from_slice(&bytes[1..34]);
+    } else if bytes.len() == 67
+        && bytes[0] == 65
+        && bytes[66] == 0xAC
+        && bytes[1] == 0x04
+        && PublicKey::from_slice(&bytes[1..66]).is_ok()
+    {
+        // P2PK with a valid uncompressed key: the type encodes the parity of y
+        buf.push(0x04 | (bytes[65] & 0x01));
+        buf.extend_from_slice(&bytes[2..34]);
+    } else {
+        write_varint(buf, bytes.len() as u64 + SPECIAL_SCRIPTS);
+        buf.extend_from_slice(bytes);
+    }
+}
+
+/// Reads a script written with [write_compressed_script]. Like Bitcoin Core, generic scripts above
+/// the maximum script size are replaced by a single `OP_RETURN`.
+pub fn read_compressed_script<R: Read>(reader: &mut R) -> io::Result<ScriptBuf> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let size = read_varint(reader)?;
+    if size >= SPECIAL_SCRIPTS {
+        let size = (size - SPECIAL_SCRIPTS) as usize;
+        if size > MAX_SCRIPT_SIZE {
+            io::copy(&mut reader.take(size as u64), &mut io::sink())?;
+            return Ok(ScriptBuf::from_bytes(vec![OP_RETURN.to_u8()]));
+        }
+        let mut bytes = vec![0u8; size];
+        reader.read_exact(&mut bytes)?;
+        return Ok(ScriptBuf::from_bytes(bytes));
+    }
+
+    let mut data = [0u8; 32];
+    let data = &mut data[..if size < 2 { 20 } else { 32 }];
+    reader.read_exact(data)?;
+    let bytes = match size {
+        0 => [&[0x76, 0xA9, 20][..], data, &[0x88, 0xAC]].concat(),
+        1 => [&[0xA9, 20][..], data, &[0x87]].concat(),
+        2 | 3 => [&[33, size as u8][..], data, &[0xAC]].concat(),
+        _ => {
+            // The key was compressed, so we need its y coordinate back
+            let key = PublicKey::from_slice(&[&[size as u8 - 2][..], data].concat())
+                .map_err(|_| invalid("invalid compressed public key"))?;
+            [&[65][..], &key.serialize_uncompressed(), &[0xAC]].concat()
+        }
+    };
+    Ok(ScriptBuf::from_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint() {
+        // Test vectors from Bitcoin Core's serialize_tests.cpp
+        let vectors: [(u64, &[u8]); 8] = [
+            (0, &[0x00]),
+            (0x7F, &[0x7F]),
+            (0x80, &[0x80, 0x00]),
+            (0x1234, &[0xA3, 0x34]),
+            (0xFFFF, &[0x82, 0xFE, 0x7F]),
+            (0x123456, &[0xC7, 0xE7, 0x56]),
+            (0xFFFF_FFFF, &[0x8E, 0xFE, 0xFE, 0xFE, 0x7F]),
+            (
+                u64::MAX,
+                &[0x80, 0xFE, 0xFE, 0xFE, 0xFE, 0xFE, 0xFE, 0xFE, 0xFE, 0x7F],
+            ),
+        ];
+        for (n, expected) in vectors {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, n);
+            assert_eq!(buf, expected);
+
+            let mut bytes = &buf[..];
+            assert_eq!(read_varint(&mut bytes).unwrap(), n);
+            assert!(bytes.is_empty());
+        }
+
+        assert!(read_varint(&mut &[0x80][..]).is_err());
+    }
+
+    #[test]
+    fn test_compression() {
+        // Test vectors from Bitcoin Core's compress_tests.cpp
+        const COIN: u64 = 100_000_000;
+        let amounts = [
+            (0, 0x0),
+            (1, 0x1),
+            (COIN / 100, 0x7),
+            (COIN, 0x9),
+            (50 * COIN, 0x32),
+            (21_000_000 * COIN, 0x1406f40),
+        ];
+        for (amount, compressed) in amounts {
+            assert_eq!(compress_amount(amount), compressed);
+            assert_eq!(decompress_amount(compressed), amount);
+        }
+
+        let compressed = |hex: &str| {
+            let mut buf = Vec::new();
+            write_compressed_script(&mut buf, &bitcoin::ScriptBuf::from_hex(hex).unwrap());
+            buf
+        };
+        let hash = "0102030405060708090a0b0c0d0e0f1011121314";
+        let p2pkh = compressed(&format!("76a914{hash}88ac"));
+        assert_eq!(p2pkh[0], 0x00);
+        assert_eq!(&p2pkh[1..], &compressed(&format!("a914{hash}87"))[1..]);
+
+        // The uncompressed generator point, whose y is even
+        let x = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        let y = "483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+        let p2pk = compressed(&format!("4104{x}{y}ac"));
+        let compressed_p2pk = compressed(&format!("2102{x}ac"));
+        assert_eq!((p2pk[0], compressed_p2pk[0]), (0x04, 0x02));
+        assert_eq!(p2pk[1..], compressed_p2pk[1..]);
+        assert_eq!(p2pk.len(), 33);
+
+        // Generic scripts are prefixed with their size plus 6
+        assert_eq!(compressed("6a0100"), [0x09, 0x6a, 0x01, 0x00]);
+
+        // Every type is decompressed back to the original script
+        for hex in [
+            format!("76a914{hash}88ac"),
+            format!("a914{hash}87"),
+            format!("2102{x}ac"),
+            format!("4104{x}{y}ac"),
+            "6a0100".to_string(),
+        ] {
+            let script = bitcoin::ScriptBuf::from_hex(&hex).unwrap();
+            let buf = compressed(&hex);
+            assert_eq!(read_compressed_script(&mut &buf[..]).unwrap(), script);
+        }
+        assert!(read_compressed_script(&mut &p2pk[..32]).is_err());
+    }
+}
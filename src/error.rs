@@ -1,6 +1,11 @@
-use bitcoin::consensus::encode::FromHexError;
+use bitcoin::absolute::LockTime;
+use bitcoin::consensus::encode::{self, FromHexError};
+use bitcoin::psbt::PsbtParseError;
+use bitcoin::{Amount, BlockHash, OutPoint, Sequence, Txid};
+use std::path::PathBuf;
 use std::{fmt, io};
 
+#[derive(Debug)]
 /// High level error type for the UTXO fetching functionality
 pub enum FetchError {
     /// Generic I/O error
@@ -13,8 +18,44 @@ pub enum FetchError {
     Transaction(reqwest::Error),
     /// Error while fetching data for the coin time computation
     CoinTime(reqwest::Error),
-    /// UTXO has less than 11 previous blocks in the chain
-    NotEnoughHeight(String),
+    /// Error while fetching a block or its hash
+    Block(reqwest::Error),
+    /// Error while fetching a Utreexo proof from the bridge
+    Proof(reqwest::Error),
+    /// The response to this URL is not in the replayed cassette
+    NotRecorded(String),
+    /// The fetched transaction doesn't have the requested txid
+    WrongTransaction(Txid),
+    /// The fetched header doesn't have the requested block hash
+    WrongBlock(BlockHash),
+    /// The backend's block at this height is not the ancestor of the processed block
+    ChainMismatch {
+        height: u32,
+        expected: BlockHash,
+        found: BlockHash,
+    },
+    /// The node returned an error to an RPC call
+    Rpc { code: i64, message: String },
+    /// The backend data (Core's `getblock` or Esplora's block transactions) has no prevout for the
+    /// input spending this outpoint
+    MissingPrevout(OutPoint),
+    /// The spent output is not in the UTXO snapshot
+    NotInSnapshot(OutPoint),
+    /// The block doesn't build on the block whose UTXO set the snapshot has
+    SnapshotMismatch { tip: BlockHash, block: BlockHash },
+    /// The block is not in the local chain of headers, so its height is unknown
+    NotInLocalChain(BlockHash),
+    /// A request was needed while only the local data is used (the URL is given)
+    Offline(String),
+    /// The transaction doesn't have the referenced output
+    MissingOutput(OutPoint),
+    /// A block spends an output whose transaction is not confirmed
+    Unconfirmed(OutPoint),
+    /// It's not possible to tell which transaction with this txid created the spent output
+    AmbiguousTxid {
+        outpoint: OutPoint,
+        spend_height: u32,
+    },
 }
 
 impl From<io::Error> for FetchError {
@@ -43,9 +84,269 @@ impl fmt::Display for FetchError {
             FetchError::Height(err) => write!(f, "Height fetching error: {}", err),
             FetchError::Transaction(err) => write!(f, "Transaction fetching error: {}", err),
             FetchError::CoinTime(err) => write!(f, "CoinTime fetching error: {}", err),
-            FetchError::NotEnoughHeight(utxo) => {
-                write!(f, "UTXO has a height less than 11: {}", utxo)
+            FetchError::Block(err) => write!(f, "Block fetching error: {}", err),
+            FetchError::Proof(err) => write!(f, "Utreexo proof fetching error: {}", err),
+            FetchError::NotRecorded(url) => {
+                write!(f, "The response for {} is not in the cassette", url)
             }
+            FetchError::WrongTransaction(txid) => {
+                write!(f, "Fetched data doesn't match transaction {}", txid)
+            }
+            FetchError::WrongBlock(block_hash) => {
+                write!(f, "Fetched data doesn't match block {}", block_hash)
+            }
+            FetchError::ChainMismatch {
+                height,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Chain mismatch at height {}: expected block {} but the backend has {}",
+                height, expected, found
+            ),
+            FetchError::Rpc { code, message } => write!(f, "RPC error {}: {}", code, message),
+            FetchError::MissingPrevout(outpoint) => {
+                write!(
+                    f,
+                    "The backend data has no prevout for the input spending {}",
+                    outpoint
+                )
+            }
+            FetchError::NotInSnapshot(outpoint) => {
+                write!(f, "Output {} is not in the UTXO snapshot", outpoint)
+            }
+            FetchError::SnapshotMismatch { tip, block } => write!(
+                f,
+                "The UTXO snapshot is at block {}, which is not the parent of block {}",
+                tip, block
+            ),
+            FetchError::NotInLocalChain(block_hash) => {
+                write!(f, "Block {} is not in the local headers", block_hash)
+            }
+            FetchError::Offline(url) => write!(
+                f,
+                "The data at {} is not in the local headers nor the UTXO snapshot, and no request \
+                 is made with local headers",
+                url
+            ),
+            FetchError::MissingOutput(outpoint) => {
+                write!(f, "Transaction has no output {}", outpoint)
+            }
+            FetchError::Unconfirmed(outpoint) => {
+                write!(f, "Spent output {} is not confirmed", outpoint)
+            }
+            FetchError::AmbiguousTxid {
+                outpoint,
+                spend_height,
+            } => write!(
+                f,
+                "Cannot tell which transaction with txid {} created the output spent at height {}",
+                outpoint.txid, spend_height
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Inconsistency found when checking the fetched UTXO data against the block
+pub enum ValidationError {
+    /// The number of fetched UTXOs doesn't match the number of non-coinbase inputs
+    InputCountMismatch { inputs: usize, utxos: usize },
+    /// The UTXO at this index is not keyed by the outpoint the block input references
+    OutpointMismatch {
+        index: usize,
+        expected: OutPoint,
+        found: OutPoint,
+    },
+    /// A transaction creates more value than the UTXOs it spends
+    NegativeFee {
+        txid: Txid,
+        input_value: Amount,
+        output_value: Amount,
+    },
+    /// The coinbase claims more than the block subsidy plus the total fees
+    CoinbaseOverclaim {
+        height: u32,
+        claimed: Amount,
+        allowed: Amount,
+    },
+    /// A coinbase output is spent with less than 100 confirmations
+    ImmatureCoinbaseSpend {
+        txid: Txid,
+        vin: usize,
+        creation_height: u32,
+    },
+    /// The BIP 68 relative lock time of an input is not satisfied
+    SequenceLock {
+        txid: Txid,
+        vin: usize,
+        sequence: Sequence,
+    },
+    /// The transaction lock time is not satisfied (using the BIP 113 median time past)
+    LockTime { txid: Txid, lock_time: LockTime },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::InputCountMismatch { inputs, utxos } => {
+                write!(
+                    f,
+                    "Block has {} inputs but {} UTXOs were given",
+                    inputs, utxos
+                )
+            }
+            ValidationError::OutpointMismatch {
+                index,
+                expected,
+                found,
+            } => write!(
+                f,
+                "UTXO #{} is keyed by {} but the block input spends {}",
+                index, found, expected
+            ),
+            ValidationError::NegativeFee {
+                txid,
+                input_value,
+                output_value,
+            } => write!(
+                f,
+                "Transaction {} spends {} but creates {}",
+                txid, input_value, output_value
+            ),
+            ValidationError::CoinbaseOverclaim {
+                height,
+                claimed,
+                allowed,
+            } => write!(
+                f,
+                "Coinbase at height {} claims {} but at most {} is allowed",
+                height, claimed, allowed
+            ),
+            ValidationError::ImmatureCoinbaseSpend {
+                txid,
+                vin,
+                creation_height,
+            } => write!(
+                f,
+                "Input {}:{} spends a coinbase output from height {} before maturity",
+                txid, vin, creation_height
+            ),
+            ValidationError::SequenceLock {
+                txid,
+                vin,
+                sequence,
+            } => write!(
+                f,
+                "Input {}:{} doesn't satisfy its relative lock time (sequence {})",
+                txid, vin, sequence
+            ),
+            ValidationError::LockTime { txid, lock_time } => {
+                write!(
+                    f,
+                    "Transaction {} is not final (lock time {})",
+                    txid, lock_time
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Error while processing a block directory
+pub enum ProcessError {
+    /// Generic I/O error
+    Io(io::Error),
+    /// The raw block couldn't be deserialized
+    Block(encode::Error),
+    /// The raw block doesn't have the expected hash
+    HashMismatch { expected: String, actual: BlockHash },
+    /// An output file already exists, and we don't overwrite it
+    OutputExists(PathBuf),
+    /// A single output file already exists, and we don't overwrite it
+    OutputFileExists(PathBuf),
+    /// There is no spent UTXOs file in the block directory
+    MissingUtxoFile(PathBuf),
+    /// Error while fetching the spent UTXOs
+    Fetch(FetchError),
+    /// The spent UTXOs are not consistent with the block
+    Invalid(Vec<ValidationError>),
+    /// A line of the batch manifest couldn't be parsed
+    Manifest(String),
+    /// The PSBT couldn't be decoded
+    Psbt(PsbtParseError),
+    /// The argument is not a txid, a raw transaction or a file with a raw transaction
+    InvalidTransaction(String),
+}
+
+impl From<io::Error> for ProcessError {
+    fn from(e: io::Error) -> Self {
+        ProcessError::Io(e)
+    }
+}
+
+impl From<encode::Error> for ProcessError {
+    fn from(e: encode::Error) -> Self {
+        ProcessError::Block(e)
+    }
+}
+
+impl From<PsbtParseError> for ProcessError {
+    fn from(e: PsbtParseError) -> Self {
+        ProcessError::Psbt(e)
+    }
+}
+
+impl From<FetchError> for ProcessError {
+    fn from(e: FetchError) -> Self {
+        ProcessError::Fetch(e)
+    }
+}
+
+impl From<ValidationError> for ProcessError {
+    fn from(e: ValidationError) -> Self {
+        ProcessError::Invalid(vec![e])
+    }
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessError::Io(err) => write!(f, "I/O error: {}", err),
+            ProcessError::Block(err) => write!(f, "Failed to deserialize block: {}", err),
+            ProcessError::HashMismatch { expected, actual } => write!(
+                f,
+                "Block hashes do not match. Expected: {}, actual: {}",
+                expected, actual
+            ),
+            ProcessError::OutputExists(dir) => write!(
+                f,
+                "One or more output files already exist in '{}'. Aborting to avoid overwriting.",
+                dir.display()
+            ),
+            ProcessError::OutputFileExists(file) => write!(
+                f,
+                "Output file '{}' already exists. Aborting to avoid overwriting.",
+                file.display()
+            ),
+            ProcessError::MissingUtxoFile(dir) => {
+                write!(f, "No spent UTXOs file found in '{}'", dir.display())
+            }
+            ProcessError::Fetch(err) => write!(f, "Error fetching spent UTXOs: {}", err),
+            ProcessError::Invalid(violations) => {
+                write!(f, "Invalid spent UTXOs:")?;
+                for violation in violations {
+                    write!(f, "\n  {}", violation)?;
+                }
+                Ok(())
+            }
+            ProcessError::Manifest(line) => write!(f, "Invalid manifest line: {}", line),
+            ProcessError::Psbt(err) => write!(f, "Invalid PSBT: {}", err),
+            ProcessError::InvalidTransaction(arg) => write!(
+                f,
+                "Not a txid, raw transaction or raw transaction file: {}",
+                arg
+            ),
         }
     }
 }
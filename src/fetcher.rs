@@ -0,0 +1,1219 @@
+use crate::cassette::Cassette;
+use crate::coin_time::{fetch_coin_time, EsploraBlock};
+use crate::error::FetchError;
+use crate::snapshot::UtxoSnapshot;
+use crate::utreexo::LeafData;
+use crate::validation::{duplicate_coinbase_heights, spendable_instance, ChainParams};
+use crate::{UtxoData, UNCONFIRMED_HEIGHT, UNCONFIRMED_TIME};
+use bitcoin::block::Header;
+use bitcoin::consensus::encode::{deserialize, deserialize_hex};
+use bitcoin::hashes::Hash;
+use bitcoin::{Amount, Block, BlockHash, Network, OutPoint, ScriptBuf, Transaction, TxOut, Txid};
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The Esplora API used by default, for mainnet.
+pub const DEFAULT_ESPLORA_URL: &str = "https://blockstream.info/api";
+
+/// Number of transactions in each page of Esplora's `/block/:hash/txs/:start_index`.
+const ESPLORA_TXS_PAGE: usize = 25;
+
+/// Minimum time between two requests to the chain APIs, to respect their rate limits.
+const REQUEST_INTERVAL: Duration = Duration::from_millis(60);
+
+/// Spaces out requests so that at most one starts per [REQUEST_INTERVAL], even when they are
+/// made concurrently.
+struct RateLimiter {
+    next_slot: tokio::sync::Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        RateLimiter {
+            next_slot: tokio::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until the next request slot is available and reserves it.
+    async fn wait(&self) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().await;
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + REQUEST_INTERVAL;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+/// Fetches spent UTXO data from the chain APIs.
+///
+/// ```no_run
+/// # async fn example(block: bitcoin::Block) -> Result<(), utxo_fetcher::FetchError> {
+/// use utxo_fetcher::SpentUtxoFetcher;
+///
+/// let fetcher = SpentUtxoFetcher::new();
+/// for (outpoint, utxo) in fetcher.fetch_block(&block).await? {
+///     println!("{}: {} sats", outpoint, utxo.txout.value.to_sat());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// A single fetcher can be shared to process many blocks: it reuses the HTTP client, the rate
+/// limiter and the caches of block headers and coin times. These are keyed by block hash, so they
+/// stay valid across reorgs.
+///
+/// Outputs of blocks registered with [SpentUtxoFetcher::add_block_outputs] are resolved locally
+/// when spent, without any request.
+pub struct SpentUtxoFetcher {
+    client: reqwest::Client,
+    rate_limiter: RateLimiter,
+    /// Coin time (MTP of the previous 11 blocks) of the UTXOs created in each block
+    coin_time_cache: Mutex<HashMap<BlockHash, u32>>,
+    /// Height and header of each known block
+    header_cache: Mutex<HashMap<BlockHash, (u32, Header)>>,
+    /// Unspent outputs created by the registered blocks
+    known_utxos: Mutex<HashMap<OutPoint, UtxoData>>,
+    /// Block hash for each block height, in the backend's best chain or our registered blocks
+    block_hash_cache: Mutex<HashMap<u32, BlockHash>>,
+    /// Base URL of the Esplora API, without a trailing slash
+    esplora_url: String,
+    /// Whether transactions are looked up in the Esplora API instead of blockchain.info
+    esplora_txs: bool,
+    /// Cassette where the responses are recorded, or from which they are replayed
+    cassette: Option<Cassette>,
+    /// UTXO set from which the spent outputs are taken instead of the chain APIs
+    snapshot: Option<UtxoSnapshot>,
+    /// Consensus parameters of the backend's network
+    params: ChainParams,
+    /// Whether the headers and block hashes come from a local chain of headers, in which case no
+    /// request is made
+    local_chain: bool,
+}
+
+impl Default for SpentUtxoFetcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpentUtxoFetcher {
+    /// Creates a fetcher with its own HTTP client and empty caches.
+    pub fn new() -> Self {
+        SpentUtxoFetcher {
+            client: reqwest::Client::new(),
+            rate_limiter: RateLimiter::new(),
+            coin_time_cache: Mutex::new(HashMap::new()),
+            header_cache: Mutex::new(HashMap::new()),
+            known_utxos: Mutex::new(HashMap::new()),
+            block_hash_cache: Mutex::new(HashMap::new()),
+            esplora_url: DEFAULT_ESPLORA_URL.to_string(),
+            esplora_txs: false,
+            cassette: None,
+            snapshot: None,
+            params: ChainParams::MAINNET,
+            local_chain: false,
+        }
+    }
+
+    /// Uses the Esplora API at `url` (e.g. "http://localhost:3002" for a regtest instance) for
+    /// every request, including the transaction lookups that go to blockchain.info by default.
+    /// This allows working with any network.
+    pub fn with_esplora_url(mut self, url: &str) -> Self {
+        self.esplora_url = url.trim_end_matches('/').to_string();
+        self.esplora_txs = true;
+        self
+    }
+
+    /// Records every response into the cassette, or serves them all from it (without making any
+    /// request nor waiting for the rate limiter), depending on its mode. See [crate::cassette].
+    pub fn with_cassette(mut self, cassette: Cassette) -> Self {
+        self.cassette = Some(cassette);
+        self
+    }
+
+    /// Takes every fetched output from the UTXO snapshot instead of the chain APIs, failing with
+    /// [FetchError::NotInSnapshot] for outputs it doesn't have. The snapshot has no block hashes
+    /// nor headers, so the coin times are fetched unless they come from [Self::with_local_chain].
+    /// Blocks must build on the snapshot tip (see [crate::snapshot]).
+    pub fn with_snapshot(mut self, snapshot: UtxoSnapshot) -> Self {
+        self.snapshot = Some(snapshot);
+        self
+    }
+
+    /// Uses the consensus parameters of `network` (mainnet by default) to read block heights from
+    /// the coinbase and to check the fetched UTXOs. It must be the network of the backend.
+    pub fn with_network(mut self, network: Network) -> Self {
+        self.params = network.into();
+        self
+    }
+
+    /// Takes every header and block hash from `headers`, the consecutive headers of the chain from
+    /// the genesis block (e.g. read with [crate::snapshot::read_headers]), and makes no request at
+    /// all: anything that isn't local fails with [FetchError::Offline], or is served from the
+    /// cassette when replaying one.
+    ///
+    /// Along with a UTXO snapshot, whose base must be in `headers` (or
+    /// [FetchError::NotInLocalChain] is returned), blocks are processed without trusting any
+    /// explorer: the snapshot has the outputs, and the headers their coin times and block hashes.
+    pub fn with_local_chain(mut self, headers: &[Header]) -> Result<Self, FetchError> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        if headers.first().map(|genesis| genesis.prev_blockhash) != Some(BlockHash::all_zeros()) {
+            return Err(invalid("the headers don't start at a genesis block").into());
+        }
+
+        let mut prev_blockhash = BlockHash::all_zeros();
+        for (height, header) in headers.iter().enumerate() {
+            if header.prev_blockhash != prev_blockhash {
+                return Err(invalid(&format!(
+                    "the header at height {} doesn't build on the previous one",
+                    height
+                ))
+                .into());
+            }
+            prev_blockhash = header.block_hash();
+            self.cache_header(height as u32, *header);
+            self.cache_block_hash(height as u32, prev_blockhash);
+        }
+        self.local_chain = true;
+        Ok(self)
+    }
+
+    /// Consensus parameters of the network in use.
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    /// Base URL of the Esplora API in use.
+    pub(crate) fn esplora_url(&self) -> &str {
+        &self.esplora_url
+    }
+
+    /// Performs a rate limited GET request and returns the response body. Request errors,
+    /// including error statuses (such as 404 for unknown objects or 429 when rate limited), are
+    /// wrapped with `error`.
+    pub(crate) async fn request_from_url(
+        &self,
+        url: &str,
+        error: fn(reqwest::Error) -> FetchError,
+    ) -> Result<String, FetchError> {
+        let bytes = self.request_bytes(url, error).await?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// Performs a rate limited GET request and returns the raw response body, recording it into
+    /// the cassette or taking it from there instead of making the request.
+    async fn request_bytes(
+        &self,
+        url: &str,
+        error: fn(reqwest::Error) -> FetchError,
+    ) -> Result<Vec<u8>, FetchError> {
+        if let Some(replayed) = self.cassette.as_ref().and_then(|c| c.replayed(url)) {
+            return replayed.ok_or_else(|| FetchError::NotRecorded(url.to_string()));
+        }
+        if self.local_chain {
+            return Err(FetchError::Offline(url.to_string()));
+        }
+
+        self.rate_limiter.wait().await;
+        let response = async {
+            let response = self.client.get(url).send().await?.error_for_status()?;
+            response.bytes().await
+        };
+        let bytes = response.await.map_err(error)?;
+        if let Some(cassette) = &self.cassette {
+            cassette.record_response(url, &bytes)?;
+        }
+        Ok(bytes.to_vec())
+    }
+
+    /// Performs a rate limited POST request with a JSON body (as JSON-RPC calls are) and returns
+    /// the response body, recording it into the cassette under `key` or taking it from there
+    /// instead of making the request. The `key` must identify the request, since the URL alone
+    /// doesn't.
+    ///
+    /// Error statuses other than 401 and 403 return their body, which describes the error, but
+    /// only successful responses are recorded.
+    pub(crate) async fn post_json(
+        &self,
+        url: &str,
+        auth: Option<(&str, &str)>,
+        body: &serde_json::Value,
+        key: &str,
+        error: fn(reqwest::Error) -> FetchError,
+    ) -> Result<Vec<u8>, FetchError> {
+        if let Some(replayed) = self.cassette.as_ref().and_then(|c| c.replayed(key)) {
+            return replayed.ok_or_else(|| FetchError::NotRecorded(key.to_string()));
+        }
+        if self.local_chain {
+            return Err(FetchError::Offline(key.to_string()));
+        }
+
+        self.rate_limiter.wait().await;
+        let response = async {
+            let mut request = self
+                .client
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.to_string());
+            if let Some((user, password)) = auth {
+                request = request.basic_auth(user, Some(password));
+            }
+            let response = request.send().await?;
+            let response = match response.status().as_u16() {
+                401 | 403 => response.error_for_status()?,
+                _ => response,
+            };
+            let success = response.status().is_success();
+            Ok((success, response.bytes().await?))
+        };
+        let (success, bytes) = response.await.map_err(error)?;
+        if let (true, Some(cassette)) = (success, &self.cassette) {
+            cassette.record_response(key, &bytes)?;
+        }
+        Ok(bytes.to_vec())
+    }
+
+    /// Returns the cached height and header of the block with the given hash.
+    pub(crate) fn cached_header(&self, block_hash: &BlockHash) -> Option<(u32, Header)> {
+        self.header_cache.lock().unwrap().get(block_hash).copied()
+    }
+
+    /// Caches the header of the block at `height`.
+    pub(crate) fn cache_header(&self, height: u32, header: Header) {
+        self.header_cache
+            .lock()
+            .unwrap()
+            .insert(header.block_hash(), (height, header));
+    }
+
+    /// Returns the cached hash of the block at `height`.
+    pub(crate) fn cached_block_hash(&self, height: u32) -> Option<BlockHash> {
+        self.block_hash_cache.lock().unwrap().get(&height).copied()
+    }
+
+    /// Caches the hash of the backend's block at `height`, unless a block is already known there.
+    pub(crate) fn cache_block_hash(&self, height: u32, block_hash: BlockHash) {
+        self.block_hash_cache
+            .lock()
+            .unwrap()
+            .entry(height)
+            .or_insert(block_hash);
+    }
+
+    /// Returns the coin time for UTXOs created in the block with `block_hash`, using the cache if
+    /// possible. This is also the median time past that applies to the block's own timelocks.
+    pub async fn coin_time(&self, block_hash: BlockHash) -> Result<u32, FetchError> {
+        if let Some(coin_time) = self.coin_time_cache.lock().unwrap().get(&block_hash) {
+            return Ok(*coin_time);
+        }
+        // If not cached, perform the computation and add to cache
+        let coin_time = fetch_coin_time(self, block_hash).await?;
+        self.coin_time_cache
+            .lock()
+            .unwrap()
+            .insert(block_hash, coin_time);
+        Ok(coin_time)
+    }
+
+    /// Returns the height of the block, from its parent if its header is known, read from the
+    /// coinbase if BIP 34 applies, or fetched otherwise. The header is cached, so the coin time of
+    /// the block can be computed from it. With a local chain (see [Self::with_local_chain]), the
+    /// parent must be in it, or [FetchError::NotInLocalChain] is returned.
+    pub async fn fetch_block_height(&self, block: &Block) -> Result<u32, FetchError> {
+        if let Some((height, _)) = self.cached_header(&block.block_hash()) {
+            return Ok(height);
+        }
+        if let Some((parent_height, _)) = self.cached_header(&block.header.prev_blockhash) {
+            let height = parent_height + 1;
+            self.cache_header(height, block.header);
+            // The blocks that build on a local chain extend it (e.g. the ones applied to a UTXO
+            // snapshot), unless it already has a block at that height
+            if self.local_chain {
+                self.cache_block_hash(height, block.block_hash());
+            }
+            return Ok(height);
+        }
+        if self.local_chain {
+            return Err(FetchError::NotInLocalChain(block.header.prev_blockhash));
+        }
+        if let Ok(height) = block.bip34_block_height() {
+            if height >= self.params.bip34_height as u64 {
+                self.cache_header(height as u32, block.header);
+                return Ok(height as u32);
+            }
+        }
+        let (height, _) = self.block_header(block.block_hash()).await?;
+        Ok(height)
+    }
+
+    /// Returns the height and header of the block with `block_hash`, using the cache if possible.
+    /// The block doesn't need to be in the backend's best chain.
+    pub async fn block_header(&self, block_hash: BlockHash) -> Result<(u32, Header), FetchError> {
+        if let Some(cached) = self.cached_header(&block_hash) {
+            return Ok(cached);
+        }
+        let url = format!("{}/block/{}", self.esplora_url, block_hash);
+        let response = self.request_from_url(&url, FetchError::Height).await?;
+
+        let block: EsploraBlock = serde_json::from_str(&response)?;
+        let (height, header) = block.into_header()?;
+        if header.block_hash() != block_hash {
+            return Err(FetchError::WrongBlock(block_hash));
+        }
+        self.cache_header(height, header);
+        Ok((height, header))
+    }
+
+    /// Fetches the hash of the block at `height` in the best chain.
+    pub async fn fetch_block_hash(&self, height: u32) -> Result<BlockHash, FetchError> {
+        let url = format!("{}/block-height/{}", self.esplora_url, height);
+        let response = self.request_from_url(&url, FetchError::Block).await?;
+
+        response
+            .trim()
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+    }
+
+    /// Returns the hash of the block at `height`, using the cache if possible.
+    pub async fn block_hash(&self, height: u32) -> Result<BlockHash, FetchError> {
+        if let Some(block_hash) = self.cached_block_hash(height) {
+            return Ok(block_hash);
+        }
+        let block_hash = self.fetch_block_hash(height).await?;
+        self.cache_block_hash(height, block_hash);
+        Ok(block_hash)
+    }
+
+    /// Returns the Utreexo leaf data of a spent output. The creating block is the one that
+    /// confirmed the parent transaction according to the backend, which must be at the creation
+    /// height of `utxo`, rather than whichever block is at that height in its best chain.
+    ///
+    /// The instances of the duplicate coinbase txids are told apart by their height instead, and
+    /// so are all the outputs with a local chain (see [Self::with_local_chain]), whose block at
+    /// each height is known.
+    pub async fn fetch_leaf_data(
+        &self,
+        prevout: OutPoint,
+        utxo: &UtxoData,
+    ) -> Result<LeafData, FetchError> {
+        let local = self.local_chain || duplicate_coinbase_heights(&prevout.txid).is_some();
+        let block_hash = match local {
+            true => self.block_hash(utxo.creation_height).await?,
+            false => match self.fetch_tx_block(&prevout.txid.to_string()).await? {
+                Some((height, block_hash)) if height == utxo.creation_height => block_hash,
+                Some(_) => return Err(FetchError::WrongTransaction(prevout.txid)),
+                None => return Err(FetchError::Unconfirmed(prevout)),
+            },
+        };
+        Ok(LeafData::new(block_hash, prevout, utxo))
+    }
+
+    /// Fetches the Utreexo inclusion proof of the outputs spent by a block from a bridge. The
+    /// `url_template` is requested with `{block_hash}` replaced, and must return JSON.
+    pub async fn fetch_utreexo_proof(
+        &self,
+        url_template: &str,
+        block_hash: BlockHash,
+    ) -> Result<serde_json::Value, FetchError> {
+        let url = url_template.replace("{block_hash}", &block_hash.to_string());
+        let response = self.request_from_url(&url, FetchError::Proof).await?;
+
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    /// Fetches the block with the given hash.
+    pub async fn fetch_block_by_hash(&self, block_hash: BlockHash) -> Result<Block, FetchError> {
+        let url = format!("{}/block/{}/raw", self.esplora_url, block_hash);
+        let raw_bytes = self.request_bytes(&url, FetchError::Block).await?;
+
+        deserialize(&raw_bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+    }
+
+    /// Registers the outputs of the block at `height`, so that later spends of them are resolved
+    /// without fetching. Also caches the block hash and header.
+    pub async fn add_block_outputs(&self, block: &Block, height: u32) -> Result<(), FetchError> {
+        let block_hash = block.block_hash();
+        self.cache_header(height, block.header);
+        self.block_hash_cache
+            .lock()
+            .unwrap()
+            .insert(height, block_hash);
+        let coin_time = self.coin_time(block_hash).await?;
+
+        let mut known_utxos = self.known_utxos.lock().unwrap();
+        for tx in &block.txdata {
+            let txid = tx.compute_txid();
+            for (vout, txout) in tx.output.iter().enumerate() {
+                // Provably unspendable outputs never show up as spent
+                if txout.script_pubkey.is_op_return() {
+                    continue;
+                }
+                let utxo = UtxoData {
+                    txout: txout.clone(),
+                    is_coinbase: tx.is_coinbase(),
+                    creation_height: height,
+                    creation_time: coin_time,
+                };
+                known_utxos.insert(OutPoint::new(txid, vout as u32), utxo);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetches the UTXO spent by each non-coinbase input of the block, in input order.
+    pub async fn fetch_block(
+        &self,
+        block: &Block,
+    ) -> Result<Vec<(OutPoint, UtxoData)>, FetchError> {
+        self.stream_block(block)
+            .map_ok(|(_, outpoint, utxo)| (outpoint, utxo))
+            .try_collect()
+            .await
+    }
+
+    /// Returns a stream with the UTXO spent by each non-coinbase input of the block, in input
+    /// order, along with the index of the input (counting from the first non-coinbase input).
+    ///
+    /// The stream is lazy: each UTXO is fetched when the consumer polls for it, so results can be
+    /// processed or persisted incrementally, and a slow consumer doesn't make requests pile up.
+    /// The height of the block is obtained first (as in [Self::fetch_block_height]), since it is
+    /// needed to tell apart the instances of duplicate txids, and then its median time past. The
+    /// latter walks the ancestors of the block, so the stream fails right away with
+    /// [FetchError::ChainMismatch] if the backend follows another chain. After an error the stream
+    /// continues with the next input.
+    ///
+    /// ```no_run
+    /// # async fn example(block: bitcoin::Block) -> Result<(), utxo_fetcher::FetchError> {
+    /// use futures::TryStreamExt;
+    /// use utxo_fetcher::SpentUtxoFetcher;
+    ///
+    /// let fetcher = SpentUtxoFetcher::new();
+    /// let mut utxos = std::pin::pin!(fetcher.stream_block(&block));
+    /// while let Some((index, outpoint, utxo)) = utxos.try_next().await? {
+    ///     println!("#{} {}: {} sats", index, outpoint, utxo.txout.value.to_sat());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stream_block<'a>(
+        &'a self,
+        block: &'a Block,
+    ) -> impl Stream<Item = Result<(usize, OutPoint, UtxoData), FetchError>> + 'a {
+        let height = async move {
+            let height = self.fetch_block_height(block).await?;
+            self.coin_time(block.block_hash()).await?;
+            // The snapshot only has the coins spent by the child of its tip
+            if let Some(snapshot) = &self.snapshot {
+                if snapshot.tip() != block.header.prev_blockhash {
+                    return Err(FetchError::SnapshotMismatch {
+                        tip: snapshot.tip(),
+                        block: block.block_hash(),
+                    });
+                }
+            }
+            Ok(height)
+        };
+        stream::once(height)
+            .map_ok(move |height| {
+                // Every transaction except the coinbase
+                let outpoints = block.txdata[1..]
+                    .iter()
+                    .flat_map(|tx| tx.input.iter().map(|txin| txin.previous_output));
+
+                stream::iter(outpoints.enumerate()).then(move |(index, outpoint)| async move {
+                    let utxo = self.fetch_spent_utxo(outpoint, height).await?;
+                    Ok((index, outpoint, utxo))
+                })
+            })
+            .try_flatten()
+    }
+
+    /// Fetches the UTXO spent by each non-coinbase input of the block, in input order, with far
+    /// fewer requests than [Self::fetch_block]: the spent outputs come from the `prevout` of each
+    /// input in the Esplora `/block/:hash/txs/:start_index` pages (25 transactions each), and the
+    /// height and coinbase status of each parent transaction from one `/tx/:txid` request.
+    ///
+    /// The pages are checked against the block's txids and outpoints, but the prevouts can't be
+    /// checked against the parent txids, as the parent transactions are not downloaded. Parents
+    /// in registered blocks (see [Self::add_block_outputs]) and the duplicate coinbase txids are
+    /// resolved as in [Self::fetch_spent_utxo].
+    pub async fn fetch_block_bulk(
+        &self,
+        block: &Block,
+    ) -> Result<Vec<(OutPoint, UtxoData)>, FetchError> {
+        let height = self.fetch_block_height(block).await?;
+        let block_hash = block.block_hash();
+        self.coin_time(block_hash).await?;
+
+        let mut prevouts = Vec::new();
+        for start in (0..block.txdata.len()).step_by(ESPLORA_TXS_PAGE) {
+            let txs = &block.txdata[start..block.txdata.len().min(start + ESPLORA_TXS_PAGE)];
+            let url = format!("{}/block/{}/txs/{}", self.esplora_url, block_hash, start);
+            let response = self.request_from_url(&url, FetchError::Block).await?;
+            let page: Vec<EsploraTx> = serde_json::from_str(&response)?;
+            if page.len() != txs.len() {
+                return Err(FetchError::WrongBlock(block_hash));
+            }
+
+            for (tx, esplora_tx) in txs.iter().zip(page).filter(|(tx, _)| !tx.is_coinbase()) {
+                let txid = tx.compute_txid();
+                if esplora_tx.txid != txid || esplora_tx.vin.len() != tx.input.len() {
+                    return Err(FetchError::WrongTransaction(txid));
+                }
+                for (txin, input) in tx.input.iter().zip(esplora_tx.vin) {
+                    let outpoint = txin.previous_output;
+                    if OutPoint::new(input.txid, input.vout) != outpoint {
+                        return Err(FetchError::WrongTransaction(txid));
+                    }
+                    let prevout = input.prevout.ok_or(FetchError::MissingPrevout(outpoint))?;
+                    let txout = TxOut {
+                        value: Amount::from_sat(prevout.value),
+                        script_pubkey: prevout.scriptpubkey,
+                    };
+                    prevouts.push((outpoint, txout));
+                }
+            }
+        }
+
+        // The height, hash and coinbase status of the parents confirmed in this block are known
+        let mut parents: HashMap<Txid, (u32, BlockHash, bool)> = block
+            .txdata
+            .iter()
+            .map(|tx| (tx.compute_txid(), (height, block_hash, tx.is_coinbase())))
+            .collect();
+        let mut utxos = Vec::with_capacity(prevouts.len());
+        for (outpoint, txout) in prevouts {
+            let registered = self.known_utxos.lock().unwrap().contains_key(&outpoint);
+            if registered || duplicate_coinbase_heights(&outpoint.txid).is_some() {
+                utxos.push((outpoint, self.fetch_spent_utxo(outpoint, height).await?));
+                continue;
+            }
+
+            let (creation_height, parent_block, is_coinbase) = match parents.get(&outpoint.txid) {
+                Some(parent) => *parent,
+                None => {
+                    let parent = self.fetch_parent(outpoint).await?;
+                    parents.insert(outpoint.txid, parent);
+                    parent
+                }
+            };
+            // Outputs can be spent in their own block, but not before
+            if creation_height > height {
+                return Err(FetchError::AmbiguousTxid {
+                    outpoint,
+                    spend_height: height,
+                });
+            }
+
+            let utxo = UtxoData {
+                txout,
+                is_coinbase,
+                creation_height,
+                creation_time: self.coin_time(parent_block).await?,
+            };
+            utxos.push((outpoint, utxo));
+        }
+        Ok(utxos)
+    }
+
+    /// Returns the height and hash of the block that confirmed the transaction that created the
+    /// output, and whether it is a coinbase, from its Esplora `/tx/:txid` data.
+    async fn fetch_parent(&self, outpoint: OutPoint) -> Result<(u32, BlockHash, bool), FetchError> {
+        let url = format!("{}/tx/{}", self.esplora_url, outpoint.txid);
+        let response = self.request_from_url(&url, FetchError::Height).await?;
+        let parent: EsploraTx = serde_json::from_str(&response)?;
+        if parent.txid != outpoint.txid {
+            return Err(FetchError::WrongTransaction(outpoint.txid));
+        }
+
+        let status = parent.status.ok_or(FetchError::Unconfirmed(outpoint))?;
+        let (Some(height), Some(block_hash)) = (status.block_height, status.block_hash) else {
+            return Err(FetchError::Unconfirmed(outpoint));
+        };
+        let is_coinbase = parent.vin.first().is_some_and(|input| input.is_coinbase);
+        Ok((height, block_hash, is_coinbase))
+    }
+
+    /// Returns the [UtxoData] of the output at `outpoint` spent by a block at `spend_height`,
+    /// taking it from the registered blocks if possible and fetching it otherwise.
+    ///
+    /// For the duplicate coinbase txids (see [crate::validation::DUPLICATE_COINBASES]) the
+    /// instance that is unspent at `spend_height` is used. For any other txid, if the parent is
+    /// confirmed above `spend_height` the explorer must be reporting another transaction with the
+    /// same txid, and [FetchError::AmbiguousTxid] is returned rather than a wrong height.
+    pub async fn fetch_spent_utxo(
+        &self,
+        outpoint: OutPoint,
+        spend_height: u32,
+    ) -> Result<UtxoData, FetchError> {
+        // Outputs of registered blocks can only be spent once, so we take them out
+        let known = self.known_utxos.lock().unwrap().remove(&outpoint);
+        let utxo = match (known, duplicate_coinbase_heights(&outpoint.txid)) {
+            (Some(utxo), _) => utxo,
+            // The snapshot has the unspent instance of the duplicate txids
+            (None, _) if self.snapshot.is_some() => self.fetch_utxo(outpoint).await?,
+            (None, Some(heights)) => {
+                let height = spendable_instance(&heights, spend_height).ok_or(
+                    FetchError::AmbiguousTxid {
+                        outpoint,
+                        spend_height,
+                    },
+                )?;
+                let block_hash = self.block_hash(height).await?;
+                self.utxo_in_block(outpoint, Some((height, block_hash)))
+                    .await?
+            }
+            (None, None) => self.fetch_utxo(outpoint).await?,
+        };
+        // A block can only spend confirmed outputs
+        if !utxo.is_confirmed() {
+            return Err(FetchError::Unconfirmed(outpoint));
+        }
+        // Outputs can be spent in their own block, but not before
+        if utxo.creation_height > spend_height {
+            return Err(FetchError::AmbiguousTxid {
+                outpoint,
+                spend_height,
+            });
+        }
+        Ok(utxo)
+    }
+
+    /// Returns the UTXO spent by each input of the transactions, in input order. Coinbase
+    /// transactions are skipped.
+    ///
+    /// The transactions don't need to be confirmed, and outputs of unconfirmed transactions get
+    /// the [UNCONFIRMED_HEIGHT] and [UNCONFIRMED_TIME] placeholders. Outputs created by one of the
+    /// given transactions are resolved without fetching, as unconfirmed, so packages that were not
+    /// broadcast yet are supported.
+    pub async fn fetch_prevouts(
+        &self,
+        transactions: &[Transaction],
+    ) -> Result<Vec<(OutPoint, UtxoData)>, FetchError> {
+        let given: HashMap<Txid, &Transaction> = transactions
+            .iter()
+            .map(|tx| (tx.compute_txid(), tx))
+            .collect();
+
+        let mut utxos = Vec::new();
+        for tx in transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            for txin in &tx.input {
+                let outpoint = txin.previous_output;
+                let utxo = match given.get(&outpoint.txid) {
+                    Some(parent) => UtxoData {
+                        txout: parent
+                            .output
+                            .get(outpoint.vout as usize)
+                            .ok_or(FetchError::MissingOutput(outpoint))?
+                            .clone(),
+                        is_coinbase: parent.is_coinbase(),
+                        creation_height: UNCONFIRMED_HEIGHT,
+                        creation_time: UNCONFIRMED_TIME,
+                    },
+                    None => self.fetch_utxo(outpoint).await?,
+                };
+                utxos.push((outpoint, utxo));
+            }
+        }
+        Ok(utxos)
+    }
+
+    /// Fetches the [UtxoData] of the output at `outpoint`. If the transaction that created it is
+    /// unconfirmed, the [UNCONFIRMED_HEIGHT] and [UNCONFIRMED_TIME] placeholders are used.
+    ///
+    /// For the duplicate coinbase txids the last instance is used, as it is the one in the UTXO
+    /// set. Use [Self::fetch_spent_utxo] to get the output spent at a given height.
+    ///
+    /// With a UTXO snapshot (see [Self::with_snapshot]), the output is taken from it.
+    pub async fn fetch_utxo(&self, outpoint: OutPoint) -> Result<UtxoData, FetchError> {
+        if let Some(snapshot) = &self.snapshot {
+            let base = snapshot.metadata.base_blockhash;
+            if self.local_chain && self.cached_header(&base).is_none() {
+                return Err(FetchError::NotInLocalChain(base));
+            }
+            let coin = snapshot
+                .get(&outpoint)
+                .ok_or(FetchError::NotInSnapshot(outpoint))?;
+            let block_hash = self.block_hash(coin.height).await?;
+            return Ok(UtxoData {
+                txout: coin.txout.clone(),
+                is_coinbase: coin.is_coinbase,
+                creation_height: coin.height,
+                creation_time: self.coin_time(block_hash).await?,
+            });
+        }
+
+        let block = match duplicate_coinbase_heights(&outpoint.txid) {
+            Some(heights) => {
+                let height = heights.iter().max().copied().expect("Two heights");
+                Some((height, self.block_hash(height).await?))
+            }
+            None => self.fetch_tx_block(&outpoint.txid.to_string()).await?,
+        };
+        self.utxo_in_block(outpoint, block).await
+    }
+
+    /// Returns the [UtxoData] of the output at `outpoint`, created in the block with the given
+    /// height and hash (or unconfirmed if `None`).
+    async fn utxo_in_block(
+        &self,
+        outpoint: OutPoint,
+        block: Option<(u32, BlockHash)>,
+    ) -> Result<UtxoData, FetchError> {
+        let creation_time = match block {
+            Some((_, block_hash)) => self.coin_time(block_hash).await?,
+            None => UNCONFIRMED_TIME,
+        };
+        let transaction = self.fetch_transaction(outpoint.txid).await?;
+
+        // Get the specific TxOut using the index
+        let tx_out = transaction
+            .output
+            .get(outpoint.vout as usize)
+            .ok_or(FetchError::MissingOutput(outpoint))?;
+
+        let utxo = UtxoData {
+            txout: tx_out.clone(),
+            is_coinbase: transaction.is_coinbase(),
+            creation_height: block.map_or(UNCONFIRMED_HEIGHT, |(height, _)| height),
+            creation_time,
+        };
+
+        Ok(utxo)
+    }
+
+    /// Returns the height and hash of the block that confirmed the transaction, or `None` if it is
+    /// still in the mempool.
+    ///
+    /// Esplora reports the block hash along with the height. blockchain.info only reports the
+    /// height, so the hash is that of the Esplora block at that height.
+    async fn fetch_tx_block(&self, txid: &str) -> Result<Option<(u32, BlockHash)>, FetchError> {
+        let url = match self.esplora_txs {
+            true => format!("{}/tx/{}/status", self.esplora_url, txid),
+            false => format!("https://blockchain.info/rawtx/{}", txid),
+        };
+        let response = self.request_from_url(&url, FetchError::Height).await?;
+
+        let parsed: serde_json::Value = serde_json::from_str(&response)?;
+
+        // Manually extract the height field (named the same in both APIs), which unconfirmed
+        // transactions don't have
+        let Some(height) = parsed["block_height"].as_u64().map(|height| height as u32) else {
+            return Ok(None);
+        };
+        let block_hash = match parsed["block_hash"].as_str() {
+            Some(block_hash) => block_hash
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            None => self.block_hash(height).await?,
+        };
+
+        Ok(Some((height, block_hash)))
+    }
+
+    /// Fetches the transaction with the given id, checking that the returned data hashes to it.
+    pub async fn fetch_transaction(&self, txid: Txid) -> Result<Transaction, FetchError> {
+        let url = match self.esplora_txs {
+            true => format!("{}/tx/{}/hex", self.esplora_url, txid),
+            false => format!("https://blockchain.info/rawtx/{}?format=hex", txid),
+        };
+        let response = self.request_from_url(&url, FetchError::Transaction).await?;
+
+        let transaction: Transaction = deserialize_hex(&response)?;
+        if transaction.compute_txid() != txid {
+            return Err(FetchError::WrongTransaction(txid));
+        }
+
+        Ok(transaction)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+/// The fields of an Esplora transaction used by [SpentUtxoFetcher::fetch_block_bulk].
+struct EsploraTx {
+    txid: Txid,
+    vin: Vec<EsploraInput>,
+    status: Option<EsploraStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EsploraInput {
+    txid: Txid,
+    vout: u32,
+    /// The spent output, which coinbase inputs don't have
+    prevout: Option<EsploraPrevout>,
+    is_coinbase: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct EsploraPrevout {
+    scriptpubkey: ScriptBuf,
+    value: u64,
+}
+
+#[derive(Debug, Deserialize)]
+/// The confirmation status, where unconfirmed transactions have no block.
+struct EsploraStatus {
+    block_height: Option<u32>,
+    block_hash: Option<BlockHash>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockEsplora;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::constants::genesis_block;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{transaction, Amount, Network, ScriptBuf, Sequence, TxIn, TxOut, Witness};
+    use reqwest::StatusCode;
+    use wiremock::ResponseTemplate;
+
+    fn spend(previous_output: OutPoint, value: u64) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_known_utxos() {
+        // Headers of the blocks from 100 to 110, with timestamps 1,100 to 1,110
+        let fetcher = SpentUtxoFetcher::new();
+        let mut prev_blockhash = BlockHash::all_zeros();
+        for height in 100..111 {
+            let header = Header {
+                prev_blockhash,
+                time: 1_000 + height,
+                ..genesis_block(Network::Bitcoin).header
+            };
+            fetcher.cache_header(height, header);
+            prev_blockhash = header.block_hash();
+        }
+
+        let mut genesis = genesis_block(Network::Bitcoin);
+        genesis.header.prev_blockhash = prev_blockhash;
+        let coinbase = genesis.txdata[0].clone();
+        let coinbase_outpoint = OutPoint::new(coinbase.compute_txid(), 0);
+
+        // The second block spends the coinbase, and then the output of its own first transaction
+        let first = spend(coinbase_outpoint, 4_000);
+        let second = spend(OutPoint::new(first.compute_txid(), 0), 3_000);
+        let mut block_coinbase = coinbase.clone();
+        block_coinbase.output[0].value = Amount::from_sat(1);
+        let mut block = genesis.clone();
+        block.header.time += 600;
+        block.header.prev_blockhash = genesis.block_hash();
+        block.txdata = vec![block_coinbase, first, second];
+
+        // With the headers cached, no request is needed
+        fetcher.add_block_outputs(&genesis, 111).await.unwrap();
+        fetcher.add_block_outputs(&block, 112).await.unwrap();
+
+        let utxos = fetcher.fetch_block(&block).await.unwrap();
+        assert_eq!(utxos.len(), 2);
+        assert_eq!(utxos[0].0, coinbase_outpoint);
+        assert!(utxos[0].1.is_coinbase);
+        assert_eq!(utxos[0].1.creation_height, 111);
+        assert_eq!(utxos[0].1.creation_time, 1_105);
+        assert_eq!(utxos[1].1.txout.value, Amount::from_sat(4_000));
+        assert_eq!(utxos[1].1.creation_height, 112);
+        assert!(!utxos[1].1.is_coinbase);
+
+        // Spent outputs are forgotten
+        assert!(!fetcher
+            .known_utxos
+            .lock()
+            .unwrap()
+            .contains_key(&coinbase_outpoint));
+
+        // Streaming yields the same UTXOs along with the input index
+        fetcher.add_block_outputs(&genesis, 111).await.unwrap();
+        fetcher.add_block_outputs(&block, 112).await.unwrap();
+        let streamed: Vec<_> = fetcher.stream_block(&block).try_collect().await.unwrap();
+        let expected: Vec<_> = utxos
+            .into_iter()
+            .enumerate()
+            .map(|(index, (outpoint, utxo))| (index, outpoint, utxo))
+            .collect();
+        assert_eq!(streamed, expected);
+
+        // An output can't be spent below its creation height
+        let unspent = OutPoint::new(block.txdata[2].compute_txid(), 0);
+        assert!(matches!(
+            fetcher.fetch_spent_utxo(unspent, 111).await,
+            Err(FetchError::AmbiguousTxid { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_prevouts_of_package() {
+        let coinbase = genesis_block(Network::Bitcoin).txdata[0].clone();
+        let parent = spend(OutPoint::new(coinbase.compute_txid(), 0), 4_000);
+        let child = spend(OutPoint::new(parent.compute_txid(), 0), 3_000);
+
+        // Both parents are given, so nothing is fetched; the coinbase has no prevouts
+        let fetcher = SpentUtxoFetcher::new();
+        let utxos = fetcher
+            .fetch_prevouts(&[coinbase.clone(), child, parent.clone()])
+            .await
+            .unwrap();
+        assert_eq!(utxos.len(), 2);
+        assert_eq!(utxos[0].0, OutPoint::new(parent.compute_txid(), 0));
+        assert_eq!(utxos[0].1.txout, parent.output[0]);
+        assert!(!utxos[0].1.is_confirmed());
+        assert_eq!(utxos[0].1.creation_time, UNCONFIRMED_TIME);
+        assert!(utxos[1].1.is_coinbase);
+        assert_eq!(utxos[1].1.creation_height, UNCONFIRMED_HEIGHT);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_utxo() {
+        let base = 1_600_000_000;
+        let mock = MockEsplora::start(&(0..13).map(|h| base + 600 * h).collect::<Vec<_>>()).await;
+        let parent = spend(OutPoint::new(Txid::all_zeros(), 0), 5_000);
+        let mempool_parent = spend(OutPoint::new(Txid::all_zeros(), 1), 4_000);
+        mock.add_transaction(&parent, Some(12)).await;
+        mock.add_transaction(&mempool_parent, None).await;
+
+        let fetcher = mock.fetcher();
+        let utxo = fetcher
+            .fetch_utxo(OutPoint::new(parent.compute_txid(), 0))
+            .await
+            .unwrap();
+        assert_eq!(utxo.txout, parent.output[0]);
+        assert!(!utxo.is_coinbase);
+        assert_eq!(utxo.creation_height, 12);
+        // The median of blocks 1 to 11
+        assert_eq!(utxo.creation_time, base + 600 * 6);
+
+        let utxo = fetcher
+            .fetch_utxo(OutPoint::new(mempool_parent.compute_txid(), 0))
+            .await
+            .unwrap();
+        assert_eq!(utxo.creation_height, UNCONFIRMED_HEIGHT);
+        assert_eq!(utxo.creation_time, UNCONFIRMED_TIME);
+        assert!(matches!(
+            fetcher
+                .fetch_spent_utxo(OutPoint::new(mempool_parent.compute_txid(), 0), 12)
+                .await,
+            Err(FetchError::Unconfirmed(_))
+        ));
+
+        assert!(matches!(
+            fetcher
+                .fetch_utxo(OutPoint::new(parent.compute_txid(), 1))
+                .await,
+            Err(FetchError::MissingOutput(_))
+        ));
+
+        // The leaf commits to the block that confirmed the parent, not the cached one at its height
+        let outpoint = OutPoint::new(parent.compute_txid(), 0);
+        let mut utxo = fetcher.fetch_utxo(outpoint).await.unwrap();
+        let fetcher = mock.fetcher();
+        fetcher.cache_block_hash(12, BlockHash::all_zeros());
+        let leaf = fetcher.fetch_leaf_data(outpoint, &utxo).await.unwrap();
+        assert_eq!(leaf.block_hash, mock.block_hash(12));
+        utxo.creation_height = 11;
+        assert!(matches!(
+            fetcher.fetch_leaf_data(outpoint, &utxo).await,
+            Err(FetchError::WrongTransaction(_))
+        ));
+        let outpoint = OutPoint::new(mempool_parent.compute_txid(), 0);
+        assert!(matches!(
+            fetcher.fetch_leaf_data(outpoint, &utxo).await,
+            Err(FetchError::Unconfirmed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_block_bulk() {
+        let mock = MockEsplora::start(&[1_000, 2_000, 3_000, 4_000, 5_000]).await;
+        let coinbase = genesis_block(Network::Bitcoin).txdata[0].clone();
+        let mut parent = spend(OutPoint::new(Txid::all_zeros(), 0), 0);
+        parent.output = (0..30)
+            .map(|vout| TxOut {
+                value: Amount::from_sat(1_000 + vout),
+                script_pubkey: ScriptBuf::new(),
+            })
+            .collect();
+        mock.add_transaction(&coinbase, Some(1)).await;
+        mock.add_transaction(&parent, Some(2)).await;
+
+        // 31 transactions, so two pages: 29 spend the parent and the last one spends the genesis
+        // coinbase and an output created in the block itself
+        let mut txdata = vec![spend(OutPoint::null(), 0)];
+        txdata.extend((0..29).map(|vout| spend(OutPoint::new(parent.compute_txid(), vout), 500)));
+        let mut last = spend(OutPoint::new(coinbase.compute_txid(), 0), 500);
+        last.input.push(TxIn {
+            previous_output: OutPoint::new(txdata[1].compute_txid(), 0),
+            ..last.input[0].clone()
+        });
+        txdata.push(last);
+        let block = Block {
+            header: Header {
+                prev_blockhash: mock.block_hash(4),
+                time: 6_000,
+                ..mock.headers[4]
+            },
+            txdata,
+        };
+
+        let mut prevouts: HashMap<OutPoint, TxOut> = (0..30)
+            .map(|vout| {
+                (
+                    OutPoint::new(parent.compute_txid(), vout),
+                    parent.output[vout as usize].clone(),
+                )
+            })
+            .collect();
+        prevouts.insert(
+            OutPoint::new(coinbase.compute_txid(), 0),
+            coinbase.output[0].clone(),
+        );
+        prevouts.insert(
+            OutPoint::new(block.txdata[1].compute_txid(), 0),
+            block.txdata[1].output[0].clone(),
+        );
+        mock.add_block_txs(&block, &prevouts).await;
+
+        let fetcher = mock.fetcher();
+        fetcher.cache_header(5, block.header);
+        let utxos = fetcher.fetch_block_bulk(&block).await.unwrap();
+        assert_eq!(utxos.len(), 31);
+        for (outpoint, utxo) in &utxos {
+            assert_eq!(utxo.txout, prevouts[outpoint]);
+        }
+        // The coin times are the median times past of blocks 1, 0 and 4
+        let metadata =
+            |utxo: &UtxoData| (utxo.creation_height, utxo.creation_time, utxo.is_coinbase);
+        assert_eq!(metadata(&utxos[0].1), (2, 2_000, false));
+        assert_eq!(metadata(&utxos[29].1), (1, 1_000, true));
+        assert_eq!(metadata(&utxos[30].1), (5, 3_000, false));
+
+        // One request per parent instead of two per input
+        let requests = mock.server.received_requests().await.unwrap();
+        let tx_requests = requests.iter().filter(|r| r.url.path().starts_with("/tx/"));
+        assert_eq!(tx_requests.count(), 2);
+
+        // The pages must have every transaction of the block
+        let last_page = format!("/block/{}/txs/25", block.block_hash());
+        mock.replace(
+            &last_page,
+            ResponseTemplate::new(200).set_body_json(Vec::<()>::new()),
+        )
+        .await;
+        assert!(matches!(
+            fetcher.fetch_block_bulk(&block).await,
+            Err(FetchError::WrongBlock(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_error_responses() {
+        let mock = MockEsplora::start(&[1_000, 2_000]).await;
+        let fetcher = mock.fetcher();
+        let tx = spend(OutPoint::new(Txid::all_zeros(), 0), 5_000);
+        let outpoint = OutPoint::new(tx.compute_txid(), 0);
+
+        // Unknown transactions get a 404
+        match fetcher.fetch_utxo(outpoint).await {
+            Err(FetchError::Height(e)) => assert_eq!(e.status(), Some(StatusCode::NOT_FOUND)),
+            result => panic!("Expected a 404 error, got {:?}", result),
+        }
+
+        // Rate limited by the explorer
+        mock.add_transaction(&tx, Some(1)).await;
+        let hex_path = format!("/tx/{}/hex", tx.compute_txid());
+        mock.replace(&hex_path, ResponseTemplate::new(429)).await;
+        match fetcher.fetch_utxo(outpoint).await {
+            Err(FetchError::Transaction(e)) => {
+                assert_eq!(e.status(), Some(StatusCode::TOO_MANY_REQUESTS))
+            }
+            result => panic!("Expected a 429 error, got {:?}", result),
+        }
+
+        // Data of another transaction
+        let other = spend(OutPoint::new(Txid::all_zeros(), 1), 5_000);
+        let other_hex = bitcoin::consensus::encode::serialize_hex(&other);
+        let tx2 = spend(OutPoint::new(Txid::all_zeros(), 2), 5_000);
+        mock.add_transaction(&tx2, Some(1)).await;
+        let hex_path = format!("/tx/{}/hex", tx2.compute_txid());
+        mock.replace(
+            &hex_path,
+            ResponseTemplate::new(200).set_body_string(other_hex),
+        )
+        .await;
+        assert!(matches!(
+            fetcher.fetch_transaction(tx2.compute_txid()).await,
+            Err(FetchError::WrongTransaction(_))
+        ));
+
+        // Malformed JSON
+        let status_path = format!("/tx/{}/status", tx2.compute_txid());
+        mock.replace(
+            &status_path,
+            ResponseTemplate::new(200).set_body_string("{\"block_"),
+        )
+        .await;
+        assert!(matches!(
+            fetcher
+                .fetch_utxo(OutPoint::new(tx2.compute_txid(), 0))
+                .await,
+            Err(FetchError::Io(_))
+        ));
+
+        // A header that doesn't hash to the block id, asked to a fetcher that didn't cache it yet
+        let block_hash = mock.block_hash(1);
+        let block = serde_json::json!({
+            "id": block_hash,
+            "height": 1,
+            "version": 2,
+            "timestamp": 2_001,
+            "merkle_root": bitcoin::TxMerkleNode::all_zeros(),
+            "previousblockhash": mock.block_hash(0),
+            "nonce": 0,
+            "bits": 0x207f_ffff,
+        });
+        let block_path = format!("/block/{}", block_hash);
+        mock.replace(&block_path, ResponseTemplate::new(200).set_body_json(block))
+            .await;
+        match mock.fetcher().block_header(block_hash).await {
+            Err(FetchError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            result => panic!("Expected invalid data, got {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let mock = MockEsplora::start(&[1_000, 2_000, 3_000, 4_000, 5_000]).await;
+        let fetcher = mock.fetcher();
+
+        // Concurrent requests are still spaced by the request interval
+        let start = Instant::now();
+        let hashes = futures::future::try_join_all((0..5).map(|h| fetcher.fetch_block_hash(h)))
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= REQUEST_INTERVAL * 4);
+        assert_eq!(hashes[4], mock.block_hash(4));
+        assert_eq!(mock.request_count().await, 5);
+    }
+}
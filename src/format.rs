@@ -0,0 +1,363 @@
+use crate::encoding::{read_varint, write_varint};
+use crate::UtxoData;
+use bitcoin::consensus::{Decodable, Encodable};
+use bitcoin::{BlockHash, OutPoint, TxOut};
+use clap::ValueEnum;
+use serde::de::{MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+/// The shape of the written spent UTXOs file.
+pub enum OutputFormat {
+    /// A JSON array of `UtxoData`, in the order the block inputs spend them.
+    #[default]
+    Json,
+    /// A JSON object mapping each spent `OutPoint` ("txid:vout") to its `UtxoData`, which can be
+    /// deserialized straight into a `HashMap<OutPoint, UtxoData>`.
+    Keyed,
+    /// A compact binary file with the consensus encoding of each outpoint and `UtxoData`.
+    Binary,
+}
+
+impl OutputFormat {
+    /// The name of the spent UTXOs file written with this format.
+    pub fn file_name(self) -> &'static str {
+        match self {
+            OutputFormat::Json | OutputFormat::Keyed => "spent_utxos.json",
+            OutputFormat::Binary => "spent_utxos.bin",
+        }
+    }
+}
+
+/// Magic bytes at the start of the binary spent UTXOs file.
+pub const BINARY_MAGIC: &[u8; 4] = b"UTXO";
+
+/// Version of the binary spent UTXOs file layout.
+pub const BINARY_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Spent UTXOs keyed by their outpoint, in the order the block inputs spend them.
+///
+/// It is (de)serialized as a map, so the JSON file has the same shape as a serialized
+/// `HashMap<OutPoint, UtxoData>`, but unlike a `HashMap` the input order is preserved.
+pub struct KeyedUtxos(pub Vec<(OutPoint, UtxoData)>);
+
+impl Serialize for KeyedUtxos {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(outpoint, utxo)| (outpoint, utxo)))
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyedUtxos {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct KeyedVisitor;
+
+        impl<'de> Visitor<'de> for KeyedVisitor {
+            type Value = KeyedUtxos;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map from outpoints to UTXO data")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(KeyedUtxos(entries))
+            }
+        }
+
+        deserializer.deserialize_map(KeyedVisitor)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+/// The contents of a spent UTXOs file, in any of the supported shapes.
+pub enum UtxoFile {
+    /// A bare list of UTXOs, whose correspondence to the block inputs is the list order.
+    List(Vec<UtxoData>),
+    /// UTXOs keyed by the outpoint that references them.
+    Keyed(KeyedUtxos),
+}
+
+impl UtxoFile {
+    /// Returns the entries in file order, with their outpoints if the file includes them.
+    pub fn entries(&self) -> Vec<(Option<OutPoint>, &UtxoData)> {
+        match self {
+            UtxoFile::List(utxos) => utxos.iter().map(|utxo| (None, utxo)).collect(),
+            UtxoFile::Keyed(keyed) => keyed
+                .0
+                .iter()
+                .map(|(outpoint, utxo)| (Some(*outpoint), utxo))
+                .collect(),
+        }
+    }
+
+    /// Returns the UTXOs keyed by outpoint, if the file includes them.
+    pub fn keyed(&self) -> Option<&KeyedUtxos> {
+        match self {
+            UtxoFile::List(_) => None,
+            UtxoFile::Keyed(keyed) => Some(keyed),
+        }
+    }
+}
+
+/// Writes the spent UTXOs to `path` with the given format.
+pub fn write_utxos(
+    utxos: &[(OutPoint, UtxoData)],
+    format: OutputFormat,
+    path: &Path,
+) -> io::Result<()> {
+    let file = File::create(path)?;
+    match format {
+        OutputFormat::Json => {
+            let list: Vec<&UtxoData> = utxos.iter().map(|(_, utxo)| utxo).collect();
+            serde_json::to_writer_pretty(file, &list)?;
+        }
+        OutputFormat::Keyed => {
+            serde_json::to_writer_pretty(file, &KeyedUtxos(utxos.to_vec()))?;
+        }
+        OutputFormat::Binary => {
+            io::BufWriter::new(file).write_all(&encode_binary(utxos))?;
+        }
+    }
+    Ok(())
+}
+
+/// Serializes the spent UTXOs with the binary layout, which is:
+///
+/// - The [BINARY_MAGIC] bytes followed by the [BINARY_VERSION] byte.
+/// - The number of entries, as a compact size.
+/// - For each entry: the outpoint (txid and u32 vout), the header code (`creation_height * 2 +
+///   is_coinbase`, as Bitcoin Core's `VARINT`), the `TxOut` (u64 value and compact size prefixed
+///   script) and the u32 `creation_time`.
+///
+/// All fixed size integers are little-endian, as in the consensus encoding.
+pub fn encode_binary(utxos: &[(OutPoint, UtxoData)]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(8 + utxos.len() * 80);
+    buf.extend_from_slice(BINARY_MAGIC);
+    buf.push(BINARY_VERSION);
+
+    // Writing to a vector cannot fail
+    bitcoin::VarInt::from(utxos.len())
+        .consensus_encode(&mut buf)
+        .unwrap();
+    for (outpoint, utxo) in utxos {
+        outpoint.consensus_encode(&mut buf).unwrap();
+        let code = ((utxo.creation_height as u64) << 1) | utxo.is_coinbase as u64;
+        write_varint(&mut buf, code);
+        utxo.txout.consensus_encode(&mut buf).unwrap();
+        utxo.creation_time.consensus_encode(&mut buf).unwrap();
+    }
+    buf
+}
+
+/// Deserializes spent UTXOs written by [encode_binary].
+pub fn decode_binary(mut bytes: &[u8]) -> io::Result<KeyedUtxos> {
+    fn invalid(e: impl fmt::Display) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+    }
+
+    let header = bytes
+        .get(..BINARY_MAGIC.len() + 1)
+        .ok_or_else(|| invalid("truncated binary UTXO header"))?;
+    if &header[..BINARY_MAGIC.len()] != BINARY_MAGIC {
+        return Err(invalid("not a binary UTXO file"));
+    }
+    let version = header[BINARY_MAGIC.len()];
+    if version != BINARY_VERSION {
+        return Err(invalid(format!(
+            "unsupported binary UTXO version {}",
+            version
+        )));
+    }
+    bytes = &bytes[header.len()..];
+
+    let count = bitcoin::VarInt::consensus_decode(&mut bytes)
+        .map_err(invalid)?
+        .0;
+    // Don't trust the count for the allocation, an entry is at least 50 bytes
+    let mut entries = Vec::with_capacity((count as usize).min(bytes.len() / 50));
+    for _ in 0..count {
+        let outpoint = OutPoint::consensus_decode(&mut bytes).map_err(invalid)?;
+        let code = read_varint(&mut bytes)?;
+        let creation_height = u32::try_from(code >> 1).map_err(invalid)?;
+        let txout = TxOut::consensus_decode(&mut bytes).map_err(invalid)?;
+        let creation_time = u32::consensus_decode(&mut bytes).map_err(invalid)?;
+
+        let utxo = UtxoData {
+            txout,
+            is_coinbase: code & 1 == 1,
+            creation_height,
+            creation_time,
+        };
+        entries.push((outpoint, utxo));
+    }
+
+    if !bytes.is_empty() {
+        return Err(invalid("trailing bytes after the binary UTXO data"));
+    }
+    Ok(KeyedUtxos(entries))
+}
+
+/// Writes a Rust source file defining `fn spent_utxos() -> HashMap<OutPoint, UtxoData>` with the
+/// spent UTXOs of the block, so they can be used in tests without parsing any file at runtime.
+///
+/// The file is meant to be brought in with `include!` inside a module where `UtxoData` is in
+/// scope. The entries are kept in a static table, which compiles much faster than one struct
+/// literal per UTXO for big blocks.
+pub fn write_rust_fixture(
+    block_hash: BlockHash,
+    utxos: &[(OutPoint, UtxoData)],
+    path: &Path,
+) -> io::Result<()> {
+    let mut file = io::BufWriter::new(File::create(path)?);
+
+    writeln!(file, "// Spent UTXOs of block {block_hash}")?;
+    writeln!(file, "// Generated by utxo_fetcher, do not edit manually.")?;
+    writeln!(file)?;
+    writeln!(
+        file,
+        "/// (txid, vout, value, script_pubkey, is_coinbase, creation_height, creation_time)"
+    )?;
+    writeln!(
+        file,
+        "const SPENT_UTXOS: &[(&str, u32, u64, &str, bool, u32, u32)] = &["
+    )?;
+    for (outpoint, utxo) in utxos {
+        writeln!(
+            file,
+            "    (\"{}\", {}, {}, \"{:x}\", {}, {}, {}),",
+            outpoint.txid,
+            outpoint.vout,
+            utxo.txout.value.to_sat(),
+            utxo.txout.script_pubkey,
+            utxo.is_coinbase,
+            utxo.creation_height,
+            utxo.creation_time,
+        )?;
+    }
+    writeln!(file, "];")?;
+    writeln!(file)?;
+    file.write_all(
+        br#"/// Returns the UTXOs spent by the block, keyed by the outpoint that references them.
+fn spent_utxos() -> ::std::collections::HashMap<::bitcoin::OutPoint, UtxoData> {
+    use ::bitcoin::{Amount, OutPoint, ScriptBuf, TxOut, Txid};
+    use ::std::str::FromStr;
+
+    SPENT_UTXOS
+        .iter()
+        .map(|&(txid, vout, value, script, is_coinbase, creation_height, creation_time)| {
+            let outpoint = OutPoint::new(Txid::from_str(txid).unwrap(), vout);
+            let utxo = UtxoData {
+                txout: TxOut {
+                    value: Amount::from_sat(value),
+                    script_pubkey: ScriptBuf::from_hex(script).unwrap(),
+                },
+                is_coinbase,
+                creation_height,
+                creation_time,
+            };
+            (outpoint, utxo)
+        })
+        .collect()
+}
+"#,
+    )?;
+    file.flush()
+}
+
+/// Simple function to load UTXO data from json (either as a list or keyed by outpoint) or from
+/// the binary format, which is detected by its magic bytes.
+/// If the file has a .zst extension it will be decompressed.
+pub fn load_utxo_data(path: impl AsRef<Path>) -> io::Result<UtxoFile> {
+    let path = path.as_ref();
+    let bytes = if path.extension().and_then(OsStr::to_str) == Some("zst") {
+        // Decompress the .zst file and read its bytes.
+        zstd::stream::decode_all(File::open(path)?)?
+    } else {
+        std::fs::read(path)?
+    };
+    if bytes.starts_with(BINARY_MAGIC) {
+        return decode_binary(&bytes).map(UtxoFile::Keyed);
+    }
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Amount, ScriptBuf, TxOut, Txid};
+    use std::collections::HashMap;
+
+    // Written by `write_rust_fixture` with `entry(0)` to `entry(2)`, to check that it compiles
+    include!("testdata/spent_utxos_fixture.rs");
+
+    fn entry(vout: u32) -> (OutPoint, UtxoData) {
+        let utxo = UtxoData {
+            txout: TxOut {
+                value: Amount::from_sat(1_000 + vout as u64),
+                script_pubkey: ScriptBuf::from_bytes(vec![0x51]),
+            },
+            is_coinbase: vout == 0,
+            creation_height: 800_000,
+            creation_time: 1_690_000_000,
+        };
+        (OutPoint::new(Txid::all_zeros(), vout), utxo)
+    }
+
+    #[test]
+    fn test_keyed_roundtrip() {
+        // Use a descending order to check that it is preserved
+        let entries: Vec<_> = (0..5).rev().map(entry).collect();
+        let json = serde_json::to_string(&KeyedUtxos(entries.clone())).unwrap();
+
+        let loaded: UtxoFile = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded, UtxoFile::Keyed(KeyedUtxos(entries.clone())));
+
+        // The same file can be loaded as the map Floresta uses
+        let map: HashMap<OutPoint, UtxoData> = serde_json::from_str(&json).unwrap();
+        assert_eq!(map, entries.iter().cloned().collect());
+
+        let list: Vec<UtxoData> = entries.into_iter().map(|(_, utxo)| utxo).collect();
+        let json = serde_json::to_string(&list).unwrap();
+        let loaded: UtxoFile = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded, UtxoFile::List(list));
+    }
+
+    #[test]
+    fn test_binary_roundtrip() {
+        let entries: Vec<_> = (0..5).map(entry).collect();
+        let bytes = encode_binary(&entries);
+        assert_eq!(&bytes[..5], b"UTXO\x01");
+
+        assert_eq!(decode_binary(&bytes).unwrap(), KeyedUtxos(entries));
+        assert!(decode_binary(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode_binary(&[&bytes[..], &[0]].concat()).is_err());
+    }
+
+    #[test]
+    fn test_write_rust_fixture() {
+        let entries: Vec<_> = (0..3).map(entry).collect();
+        let path = std::env::temp_dir().join(format!("fixture_{}.rs", std::process::id()));
+        write_rust_fixture(BlockHash::all_zeros(), &entries, &path).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let txid = Txid::all_zeros();
+        assert!(written.contains(&format!(
+            "    (\"{txid}\", 0, 1000, \"51\", true, 800000, 1690000000),\n"
+        )));
+        assert_eq!(written, include_str!("testdata/spent_utxos_fixture.rs"));
+        assert_eq!(spent_utxos(), entries.into_iter().collect());
+    }
+}
@@ -0,0 +1,380 @@
+//! Bitcoin Core's `getblock <hash> 3` output as a source of spent UTXOs. At verbosity 3, every
+//! non-coinbase input embeds its `prevout` with the value, script, creation height and whether it
+//! was created by a coinbase, so only the coin times are computed, from the block headers.
+//!
+//! The JSON is read from a file with [read_getblock] or requested to a node with [CoreRpc], and is
+//! cross-checked against the raw block by [spent_utxos_from_getblock].
+
+use crate::fetcher::SpentUtxoFetcher;
+use crate::{FetchError, UtxoData};
+use bitcoin::{Amount, Block, BlockHash, OutPoint, ScriptBuf, Txid};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, Deserialize)]
+/// The fields of the `getblock` verbosity 3 result that are needed to build the spent UTXOs.
+pub struct GetBlock {
+    pub hash: BlockHash,
+    pub height: u32,
+    pub tx: Vec<GetBlockTx>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetBlockTx {
+    pub txid: Txid,
+    pub vin: Vec<GetBlockInput>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+/// A transaction input. Coinbase inputs have no outpoint nor prevout.
+pub struct GetBlockInput {
+    pub txid: Option<Txid>,
+    pub vout: Option<u32>,
+    pub prevout: Option<Prevout>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+/// The output spent by an input, as found in the node's UTXO set.
+pub struct Prevout {
+    /// Whether the output was created by a coinbase transaction
+    pub generated: bool,
+    pub height: u32,
+    #[serde(with = "bitcoin::amount::serde::as_btc")]
+    pub value: Amount,
+    #[serde(rename = "scriptPubKey")]
+    pub script_pubkey: PrevoutScript,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PrevoutScript {
+    pub hex: ScriptBuf,
+}
+
+/// Reads a file with the JSON result of `getblock <hash> 3` (e.g. as written by `bitcoin-cli`).
+pub fn read_getblock(path: &Path) -> Result<GetBlock, FetchError> {
+    let content = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Returns the UTXOs spent by the block from its `getblock` data, in input order, after checking
+/// that it describes the same block, at the same height (see
+/// [SpentUtxoFetcher::fetch_block_height]), transactions and outpoints. The coin times are computed
+/// from the headers fetched by `fetcher`.
+pub async fn spent_utxos_from_getblock(
+    fetcher: &SpentUtxoFetcher,
+    block: &Block,
+    getblock: &GetBlock,
+) -> Result<Vec<(OutPoint, UtxoData)>, FetchError> {
+    let block_hash = block.block_hash();
+    if getblock.hash != block_hash || getblock.tx.len() != block.txdata.len() {
+        return Err(FetchError::WrongBlock(block_hash));
+    }
+    // This also caches the header, for the coin time of the outputs created in this block
+    if fetcher.fetch_block_height(block).await? != getblock.height {
+        return Err(FetchError::WrongBlock(block_hash));
+    }
+
+    let mut coin_times: HashMap<u32, u32> = HashMap::new();
+    let mut utxos = Vec::new();
+    for (tx, json_tx) in block.txdata.iter().zip(&getblock.tx).skip(1) {
+        let txid = tx.compute_txid();
+        if json_tx.txid != txid || json_tx.vin.len() != tx.input.len() {
+            return Err(FetchError::WrongTransaction(txid));
+        }
+
+        for (txin, json_input) in tx.input.iter().zip(&json_tx.vin) {
+            let outpoint = txin.previous_output;
+            if json_input.txid != Some(outpoint.txid) || json_input.vout != Some(outpoint.vout) {
+                return Err(FetchError::WrongTransaction(txid));
+            }
+            let prevout = json_input
+                .prevout
+                .as_ref()
+                .ok_or(FetchError::MissingPrevout(outpoint))?;
+
+            let creation_time = match coin_times.get(&prevout.height) {
+                Some(coin_time) => *coin_time,
+                None => {
+                    // The backend may not have reached this same block yet
+                    let creating_block = match prevout.height == getblock.height {
+                        true => block_hash,
+                        false => fetcher.block_hash(prevout.height).await?,
+                    };
+                    let coin_time = fetcher.coin_time(creating_block).await?;
+                    coin_times.insert(prevout.height, coin_time);
+                    coin_time
+                }
+            };
+
+            let utxo = UtxoData {
+                txout: bitcoin::TxOut {
+                    value: prevout.value,
+                    script_pubkey: prevout.script_pubkey.hex.clone(),
+                },
+                is_coinbase: prevout.generated,
+                creation_height: prevout.height,
+                creation_time,
+            };
+            utxos.push((outpoint, utxo));
+        }
+    }
+    Ok(utxos)
+}
+
+/// A Bitcoin Core JSON-RPC endpoint, to request the `getblock` data of blocks. The calls are made
+/// by a [SpentUtxoFetcher], so they share its client and rate limit, and its cassette records
+/// them by method and parameters.
+pub struct CoreRpc {
+    url: String,
+    /// The RPC user and password
+    auth: Option<(String, String)>,
+}
+
+impl CoreRpc {
+    /// Uses the node at `url` (e.g. `http://127.0.0.1:8332`), with the user and password of the
+    /// URL if it has them.
+    pub fn new(url: &str) -> Result<Self, FetchError> {
+        let mut url = reqwest::Url::parse(url).map_err(|e| {
+            FetchError::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+        })?;
+
+        let auth = match url.username() {
+            "" => None,
+            user => Some((
+                user.to_string(),
+                url.password().unwrap_or_default().to_string(),
+            )),
+        };
+        // The credentials are sent in the authorization header instead
+        let _ = url.set_username("");
+        let _ = url.set_password(None);
+
+        Ok(CoreRpc {
+            url: url.to_string(),
+            auth,
+        })
+    }
+
+    /// Authenticates with the node's cookie file (`.cookie` in its data directory), which has the
+    /// user and password separated by a colon.
+    pub fn with_cookie(mut self, cookie_file: &Path) -> Result<Self, FetchError> {
+        let cookie = std::fs::read_to_string(cookie_file)?;
+        let (user, password) = cookie.trim().split_once(':').ok_or_else(|| {
+            FetchError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "the cookie file has no user and password",
+            ))
+        })?;
+
+        self.auth = Some((user.to_string(), password.to_string()));
+        Ok(self)
+    }
+
+    /// Requests `getblock <block_hash> 3` through `fetcher`.
+    pub async fn getblock(
+        &self,
+        fetcher: &SpentUtxoFetcher,
+        block_hash: BlockHash,
+    ) -> Result<GetBlock, FetchError> {
+        let result = self
+            .call(fetcher, "getblock", json!([block_hash, 3]))
+            .await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    /// Calls the RPC method and returns its result. In cassettes, the call is keyed by the URL
+    /// (without the credentials) followed by the method and the parameters.
+    async fn call(
+        &self,
+        fetcher: &SpentUtxoFetcher,
+        method: &str,
+        params: Value,
+    ) -> Result<Value, FetchError> {
+        let key = format!("{} {} {}", self.url, method, params);
+        let body = json!({
+            "jsonrpc": "1.0",
+            "id": "utxo_fetcher",
+            "method": method,
+            "params": params,
+        });
+        let auth = self
+            .auth
+            .as_ref()
+            .map(|(user, password)| (user.as_str(), password.as_str()));
+
+        // Failed calls have a JSON body with the error, except authentication failures
+        let bytes = fetcher
+            .post_json(&self.url, auth, &body, &key, FetchError::Block)
+            .await?;
+        let mut response: Value = serde_json::from_slice(&bytes)?;
+        match response["error"].take() {
+            Value::Null => Ok(response["result"].take()),
+            error => Err(FetchError::Rpc {
+                code: error["code"].as_i64().unwrap_or_default(),
+                message: error["message"].as_str().unwrap_or_default().to_string(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cassette::Cassette;
+    use crate::mock::MockEsplora;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{absolute, transaction, Sequence, Transaction, TxIn, TxMerkleNode, Witness};
+    use wiremock::matchers::{body_partial_json, method};
+    use wiremock::{Mock, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_spent_utxos_from_getblock() {
+        let mock = MockEsplora::start(&[1_000, 2_000, 3_000, 4_000]).await;
+        let spending_tx = |outpoints: &[OutPoint]| Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: outpoints
+                .iter()
+                .map(|outpoint| TxIn {
+                    previous_output: *outpoint,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: vec![],
+        };
+        let coinbase = spending_tx(&[OutPoint::null()]);
+        let first = OutPoint::new(Txid::from_byte_array([1; 32]), 0);
+        let second = OutPoint::new(Txid::from_byte_array([2; 32]), 3);
+        let block = Block {
+            header: bitcoin::block::Header {
+                prev_blockhash: mock.block_hash(3),
+                merkle_root: TxMerkleNode::all_zeros(),
+                ..mock.headers[3]
+            },
+            txdata: vec![coinbase, spending_tx(&[first, second])],
+        };
+
+        let json = json!({
+            "hash": block.block_hash(),
+            "height": 4,
+            "confirmations": 1,
+            "tx": [
+                { "txid": block.txdata[0].compute_txid(), "vin": [{ "coinbase": "00" }] },
+                {
+                    "txid": block.txdata[1].compute_txid(),
+                    "vin": [
+                        {
+                            "txid": first.txid,
+                            "vout": 0,
+                            "prevout": {
+                                "generated": true,
+                                "height": 2,
+                                "value": 50.0,
+                                "scriptPubKey": { "hex": "51", "type": "nonstandard" },
+                            },
+                        },
+                        {
+                            "txid": second.txid,
+                            "vout": 3,
+                            "prevout": {
+                                "generated": false,
+                                "height": 4,
+                                "value": 0.00012345,
+                                "scriptPubKey": { "hex": "", "type": "nonstandard" },
+                            },
+                        },
+                    ],
+                },
+            ],
+        });
+        let getblock: GetBlock = serde_json::from_value(json.clone()).unwrap();
+
+        // The coinbase has no BIP 34 height, so the height of the block is fetched
+        mock.add_block(&block, 4).await;
+        let fetcher = mock.fetcher();
+        let mut other = getblock.clone();
+        other.height = 3;
+        assert!(matches!(
+            spent_utxos_from_getblock(&fetcher, &block, &other).await,
+            Err(FetchError::WrongBlock(_))
+        ));
+        let utxos = spent_utxos_from_getblock(&fetcher, &block, &getblock)
+            .await
+            .unwrap();
+        assert_eq!(utxos[0].0, first);
+        assert_eq!(utxos[0].1.txout.value, Amount::from_int_btc(50));
+        assert_eq!(utxos[0].1.txout.script_pubkey.as_bytes(), [0x51]);
+        assert!(utxos[0].1.is_coinbase);
+        // The coin times are the median times past of blocks 1 and 3
+        assert_eq!(utxos[0].1.creation_time, 2_000);
+        assert_eq!(utxos[1].1.txout.value, Amount::from_sat(12_345));
+        assert_eq!(
+            (utxos[1].1.creation_height, utxos[1].1.creation_time),
+            (4, 3_000)
+        );
+
+        // The data must match the raw block
+        other = getblock.clone();
+        other.tx[1].vin[1].vout = Some(2);
+        assert!(matches!(
+            spent_utxos_from_getblock(&fetcher, &block, &other).await,
+            Err(FetchError::WrongTransaction(_))
+        ));
+        other.tx[1].vin[1].vout = Some(3);
+        other.tx[1].vin[1].prevout = None;
+        assert!(matches!(
+            spent_utxos_from_getblock(&fetcher, &block, &other).await,
+            Err(FetchError::MissingPrevout(outpoint)) if outpoint == second
+        ));
+
+        // The same data through RPC, authenticated with the URL credentials
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "method": "getblock" })))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "result": json, "error": null })),
+            )
+            .mount(&mock.server)
+            .await;
+        let address = mock.server.address();
+        let rpc = CoreRpc::new(&format!("http://user:pass@{}/", address)).unwrap();
+        let path = std::env::temp_dir().join(format!("rpc_cassette_{}.jsonl", std::process::id()));
+        let recording = mock
+            .fetcher()
+            .with_cassette(Cassette::record(&path).unwrap());
+        let rpc_getblock = rpc.getblock(&recording, block.block_hash()).await.unwrap();
+        assert_eq!(rpc_getblock.tx.len(), 2);
+        let request = mock
+            .server
+            .received_requests()
+            .await
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert!(request.headers.contains_key("authorization"));
+
+        // The call is recorded by method and parameters, without the credentials
+        let cassette = std::fs::read_to_string(&path).unwrap();
+        let key = format!(
+            "http://{}/ getblock [\"{}\",3]",
+            address,
+            block.block_hash()
+        );
+        assert!(cassette.contains(&serde_json::to_string(&key).unwrap()));
+        assert!(!cassette.contains("pass"));
+
+        let requests = mock.request_count().await;
+        let replaying = SpentUtxoFetcher::new().with_cassette(Cassette::replay(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let replayed = rpc.getblock(&replaying, block.block_hash()).await.unwrap();
+        assert_eq!(replayed.hash, block.block_hash());
+        assert_eq!(mock.request_count().await, requests);
+        assert!(matches!(
+            rpc.getblock(&replaying, BlockHash::all_zeros()).await,
+            Err(FetchError::NotRecorded(_))
+        ));
+    }
+}
@@ -0,0 +1,64 @@
+//! Fetches the UTXOs spent by a Bitcoin block, with the metadata needed to validate it: whether
+//! each output comes from a coinbase, its creation height and its BIP 68 creation time.
+//!
+//! The entry point is [SpentUtxoFetcher], which queries the chain APIs with a shared rate limit
+//! and caches. The [format](mod@format) module reads and writes the spent UTXOs files, [validation] checks
+//! the fetched data against the block, [timelock] computes when the locks of a transaction are
+//! satisfied, [psbt] fills PSBT inputs with the outputs they spend, [undo] and [utreexo] encode
+//! the spent outputs as Bitcoin Core undo data and Utreexo leaves, [getblock] and [snapshot] build
+//! them from Bitcoin Core's `getblock` output and UTXO snapshots instead, [cassette] records and
+//! replays the API responses, and [block_dir] implements the block directory workflow used by the
+//! `utxo_fetcher` CLI.
+
+pub mod block_dir;
+pub mod cassette;
+pub mod coin_time;
+pub mod diff;
+mod encoding;
+pub mod error;
+pub mod fetcher;
+pub mod format;
+pub mod getblock;
+#[cfg(test)]
+mod mock;
+pub mod psbt;
+pub mod snapshot;
+pub mod timelock;
+pub mod undo;
+pub mod utreexo;
+pub mod validation;
+
+pub use error::FetchError;
+pub use fetcher::SpentUtxoFetcher;
+
+use bitcoin::TxOut;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+/// Represents an unspent transaction output (UTXO) with additional metadata for validation.
+pub struct UtxoData {
+    /// The unspent transaction output.
+    pub txout: TxOut,
+    /// Whether this output was created by a coinbase transaction.
+    pub is_coinbase: bool,
+    /// The block height at which the UTXO was confirmed, or [UNCONFIRMED_HEIGHT].
+    pub creation_height: u32,
+    /// The creation time of the UTXO, defined by BIP 68 as the median time past (MTP) of the
+    /// block preceding the confirming block, or [UNCONFIRMED_TIME].
+    pub creation_time: u32,
+}
+
+/// The `creation_height` of outputs created by unconfirmed transactions. This is the height Bitcoin
+/// Core gives to mempool coins (`MEMPOOL_HEIGHT`).
+pub const UNCONFIRMED_HEIGHT: u32 = 0x7FFF_FFFF;
+
+/// The `creation_time` of outputs created by unconfirmed transactions.
+pub const UNCONFIRMED_TIME: u32 = u32::MAX;
+
+impl UtxoData {
+    /// Whether the output was created by a confirmed transaction, i.e. it doesn't have the
+    /// [UNCONFIRMED_HEIGHT] and [UNCONFIRMED_TIME] placeholders.
+    pub fn is_confirmed(&self) -> bool {
+        self.creation_height != UNCONFIRMED_HEIGHT
+    }
+}
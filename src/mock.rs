@@ -0,0 +1,185 @@
+//! A mock Esplora server for the offline tests. It serves a chain of synthetic headers and the
+//! transactions added to it, like the real endpoints do.
+
+use crate::SpentUtxoFetcher;
+use bitcoin::block::{Header, Version};
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::hashes::Hash;
+use bitcoin::{Block, BlockHash, CompactTarget, OutPoint, Transaction, TxMerkleNode, TxOut};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+pub(crate) struct MockEsplora {
+    pub server: MockServer,
+    /// The headers of the chain, by height
+    pub headers: Vec<Header>,
+}
+
+impl MockEsplora {
+    /// Starts a server with a chain of blocks with the given timestamps, from the genesis block.
+    pub async fn start(timestamps: &[u32]) -> Self {
+        let mut headers: Vec<Header> = Vec::with_capacity(timestamps.len());
+        for time in timestamps {
+            headers.push(Header {
+                version: Version::TWO,
+                prev_blockhash: headers
+                    .last()
+                    .map_or(BlockHash::all_zeros(), Header::block_hash),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: *time,
+                bits: CompactTarget::from_consensus(0x207f_ffff),
+                nonce: 0,
+            });
+        }
+
+        let mock = MockEsplora {
+            server: MockServer::start().await,
+            headers,
+        };
+        for (height, header) in mock.headers.iter().enumerate() {
+            let block_hash = header.block_hash().to_string();
+            let batch: Vec<Value> = (height.saturating_sub(9)..=height)
+                .rev()
+                .map(|height| block_json(height, &mock.headers[height]))
+                .collect();
+
+            let hash_response = ResponseTemplate::new(200).set_body_string(block_hash.clone());
+            mock.mount(&format!("/block-height/{}", height), hash_response)
+                .await;
+            let block_response =
+                ResponseTemplate::new(200).set_body_json(block_json(height, header));
+            mock.mount(&format!("/block/{}", block_hash), block_response)
+                .await;
+            let batch_response = ResponseTemplate::new(200).set_body_json(batch);
+            mock.mount(&format!("/blocks/{}", height), batch_response)
+                .await;
+        }
+        mock
+    }
+
+    /// Returns a fetcher that makes all its requests to the mock server.
+    pub fn fetcher(&self) -> SpentUtxoFetcher {
+        SpentUtxoFetcher::new().with_esplora_url(&self.server.uri())
+    }
+
+    /// Returns the hash of the block at `height`.
+    pub fn block_hash(&self, height: u32) -> BlockHash {
+        self.headers[height as usize].block_hash()
+    }
+
+    /// Serves the transaction, as confirmed in the block at `height` or unconfirmed if `None`.
+    pub async fn add_transaction(&self, tx: &Transaction, height: Option<u32>) {
+        let txid = tx.compute_txid();
+        let status = match height {
+            Some(height) => json!({
+                "confirmed": true,
+                "block_height": height,
+                "block_hash": self.block_hash(height),
+                "block_time": self.headers[height as usize].time,
+            }),
+            None => json!({ "confirmed": false }),
+        };
+
+        let mut tx_json = tx_json(tx, &HashMap::new());
+        tx_json["status"] = status.clone();
+        let tx_response = ResponseTemplate::new(200).set_body_json(tx_json);
+        self.mount(&format!("/tx/{}", txid), tx_response).await;
+        let status_response = ResponseTemplate::new(200).set_body_json(status);
+        self.mount(&format!("/tx/{}/status", txid), status_response)
+            .await;
+        let hex_response = ResponseTemplate::new(200).set_body_string(serialize_hex(tx));
+        self.mount(&format!("/tx/{}/hex", txid), hex_response).await;
+    }
+
+    /// Serves the `/block/:hash` data of a block that is not part of the chain, such as a block
+    /// being processed, as if it was at `height`.
+    pub async fn add_block(&self, block: &Block, height: usize) {
+        let response = ResponseTemplate::new(200).set_body_json(block_json(height, &block.header));
+        self.mount(&format!("/block/{}", block.block_hash()), response)
+            .await;
+    }
+
+    /// Serves the `/block/:hash/txs/:start_index` pages of the block, with the spent outputs in
+    /// `prevouts` (inputs without one have a null prevout, like coinbase inputs).
+    pub async fn add_block_txs(&self, block: &Block, prevouts: &HashMap<OutPoint, TxOut>) {
+        let block_hash = block.block_hash();
+        for (page, txs) in block.txdata.chunks(25).enumerate() {
+            let txs: Vec<Value> = txs.iter().map(|tx| tx_json(tx, prevouts)).collect();
+            let response = ResponseTemplate::new(200).set_body_json(txs);
+            self.mount(
+                &format!("/block/{}/txs/{}", block_hash, page * 25),
+                response,
+            )
+            .await;
+        }
+    }
+
+    /// Serves `response` to the GET requests for `url_path`.
+    pub async fn mount(&self, url_path: &str, response: ResponseTemplate) {
+        Mock::given(method("GET"))
+            .and(path(url_path))
+            .respond_with(response)
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Serves `response` to the GET requests for `url_path` instead of the mounted response.
+    pub async fn replace(&self, url_path: &str, response: ResponseTemplate) {
+        // The highest priority, while mocks have 5 by default
+        Mock::given(method("GET"))
+            .and(path(url_path))
+            .respond_with(response)
+            .with_priority(1)
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Returns the number of requests received by the server.
+    pub async fn request_count(&self) -> usize {
+        self.server.received_requests().await.unwrap().len()
+    }
+}
+
+/// The block JSON returned by the `/block/:hash` and `/blocks/:height` endpoints.
+fn block_json(height: usize, header: &Header) -> Value {
+    let prev_blockhash = match height {
+        0 => Value::Null,
+        _ => json!(header.prev_blockhash),
+    };
+    json!({
+        "id": header.block_hash(),
+        "height": height,
+        "version": header.version.to_consensus(),
+        "timestamp": header.time,
+        "tx_count": 1,
+        "merkle_root": header.merkle_root,
+        "previousblockhash": prev_blockhash,
+        "nonce": header.nonce,
+        "bits": header.bits.to_consensus(),
+    })
+}
+
+/// The Esplora JSON of a transaction, without its outputs nor status.
+fn tx_json(tx: &Transaction, prevouts: &HashMap<OutPoint, TxOut>) -> Value {
+    let vin: Vec<Value> = tx
+        .input
+        .iter()
+        .map(|txin| {
+            let prevout = prevouts.get(&txin.previous_output).map(|txout| {
+                json!({
+                    "scriptpubkey": txout.script_pubkey,
+                    "value": txout.value.to_sat(),
+                })
+            });
+            json!({
+                "txid": txin.previous_output.txid,
+                "vout": txin.previous_output.vout,
+                "prevout": prevout,
+                "is_coinbase": tx.is_coinbase(),
+            })
+        })
+        .collect();
+    json!({ "txid": tx.compute_txid(), "vin": vin })
+}
@@ -0,0 +1,183 @@
+//! Filling the inputs of a PSBT (BIP 174) with the previous outputs they spend, which signers
+//! need to compute the sighashes and the fee.
+
+use crate::error::ProcessError;
+use crate::fetcher::SpentUtxoFetcher;
+use crate::FetchError;
+use bitcoin::psbt::{Input, PsbtParseError};
+use bitcoin::{OutPoint, Psbt, Script, Transaction, WitnessVersion};
+
+/// Magic bytes at the start of a binary PSBT.
+pub const PSBT_MAGIC: &[u8; 5] = b"psbt\xff";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// The UTXO fields that were filled for a PSBT input.
+pub struct FilledInput {
+    /// Whether `non_witness_utxo` (the whole previous transaction) was set.
+    pub non_witness_utxo: bool,
+    /// Whether `witness_utxo` (only the spent output) was set.
+    pub witness_utxo: bool,
+}
+
+/// Decodes a PSBT in binary or base64 form. Returns the PSBT and whether it was binary.
+pub fn decode_psbt(bytes: &[u8]) -> Result<(Psbt, bool), ProcessError> {
+    if bytes.starts_with(PSBT_MAGIC) {
+        let psbt = Psbt::deserialize(bytes).map_err(PsbtParseError::PsbtEncoding)?;
+        return Ok((psbt, true));
+    }
+    let base64 = String::from_utf8_lossy(bytes);
+    Ok((base64.trim().parse()?, false))
+}
+
+/// Fetches the previous transaction of every input and fills its missing UTXO fields, depending
+/// on the type of the spent output (see [fill_input]). Returns what was filled for each input.
+pub async fn fill_psbt_inputs(
+    fetcher: &SpentUtxoFetcher,
+    psbt: &mut Psbt,
+) -> Result<Vec<FilledInput>, FetchError> {
+    let mut filled = Vec::with_capacity(psbt.inputs.len());
+    for (txin, input) in psbt.unsigned_tx.input.iter().zip(&mut psbt.inputs) {
+        if input.non_witness_utxo.is_some() && input.witness_utxo.is_some() {
+            filled.push(FilledInput::default());
+            continue;
+        }
+        let prev_tx = fetcher.fetch_transaction(txin.previous_output.txid).await?;
+        filled.push(fill_input(input, txin.previous_output, &prev_tx)?);
+    }
+    Ok(filled)
+}
+
+/// Fills the missing UTXO fields of the input spending `outpoint` from its previous transaction.
+///
+/// Legacy inputs get `non_witness_utxo`, and taproot inputs get `witness_utxo`, which is all they
+/// sign. Segwit v0 inputs get both: `witness_utxo` is enough to sign, but many signers also
+/// require the previous transaction to verify the amount, as the v0 sighash doesn't commit to
+/// the other inputs' amounts. P2SH outputs are only treated as segwit if the input has a redeem
+/// script that is a witness program.
+pub fn fill_input(
+    input: &mut Input,
+    outpoint: OutPoint,
+    prev_tx: &Transaction,
+) -> Result<FilledInput, FetchError> {
+    let txout = prev_tx
+        .output
+        .get(outpoint.vout as usize)
+        .ok_or(FetchError::MissingOutput(outpoint))?;
+
+    let (non_witness, witness) =
+        match witness_version(&txout.script_pubkey, input.redeem_script.as_deref()) {
+            None => (true, false),
+            Some(WitnessVersion::V0) => (true, true),
+            Some(_) => (false, true),
+        };
+
+    let mut filled = FilledInput::default();
+    if non_witness && input.non_witness_utxo.is_none() {
+        input.non_witness_utxo = Some(prev_tx.clone());
+        filled.non_witness_utxo = true;
+    }
+    if witness && input.witness_utxo.is_none() {
+        input.witness_utxo = Some(txout.clone());
+        filled.witness_utxo = true;
+    }
+    Ok(filled)
+}
+
+/// Returns the witness version of the spent output, looking into the redeem script for P2SH.
+fn witness_version(
+    script_pubkey: &Script,
+    redeem_script: Option<&Script>,
+) -> Option<WitnessVersion> {
+    if script_pubkey.is_p2sh() {
+        redeem_script?.witness_version()
+    } else {
+        script_pubkey.witness_version()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::hashes::Hash;
+    use bitcoin::key::TapTweak;
+    use bitcoin::{
+        transaction, Amount, ScriptBuf, Sequence, TxIn, TxOut, Txid, WPubkeyHash, Witness,
+        XOnlyPublicKey,
+    };
+    use std::str::FromStr;
+
+    #[test]
+    fn test_fill_input() {
+        let wpkh = ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros());
+        let key = XOnlyPublicKey::from_str(
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let p2tr = ScriptBuf::new_p2tr_tweaked(key.dangerous_assume_tweaked());
+        let p2sh_wpkh = ScriptBuf::new_p2sh(&wpkh.script_hash());
+        let outputs = [ScriptBuf::new_op_return([]), wpkh.clone(), p2tr, p2sh_wpkh];
+
+        let prev_tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: outputs
+                .iter()
+                .map(|script_pubkey| TxOut {
+                    value: Amount::from_sat(1_000),
+                    script_pubkey: script_pubkey.clone(),
+                })
+                .collect(),
+        };
+        let spend = Transaction {
+            input: (0..5)
+                .map(|vout| TxIn {
+                    previous_output: OutPoint::new(prev_tx.compute_txid(), vout),
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            ..prev_tx.clone()
+        };
+        let mut psbt = Psbt::from_unsigned_tx(spend).unwrap();
+        psbt.inputs[3].redeem_script = Some(wpkh);
+
+        let filled: Vec<_> = (0..4)
+            .map(|i| {
+                let outpoint = psbt.unsigned_tx.input[i].previous_output;
+                let filled = fill_input(&mut psbt.inputs[i], outpoint, &prev_tx).unwrap();
+                (filled.non_witness_utxo, filled.witness_utxo)
+            })
+            .collect();
+        // Legacy, segwit v0, taproot and nested segwit v0
+        assert_eq!(
+            filled,
+            [(true, false), (true, true), (false, true), (true, true)]
+        );
+        assert_eq!(psbt.inputs[2].witness_utxo.as_ref(), prev_tx.output.get(2));
+
+        // Already filled fields are kept
+        let filled = fill_input(
+            &mut psbt.inputs[1],
+            OutPoint::new(Txid::all_zeros(), 1),
+            &prev_tx,
+        );
+        assert_eq!(filled.unwrap(), FilledInput::default());
+
+        let outpoint = psbt.unsigned_tx.input[4].previous_output;
+        assert!(matches!(
+            fill_input(&mut psbt.inputs[4], outpoint, &prev_tx),
+            Err(FetchError::MissingOutput(_))
+        ));
+
+        // Both encodings are accepted
+        let (decoded, binary) = decode_psbt(psbt.to_string().as_bytes()).unwrap();
+        assert!(!binary);
+        assert_eq!(decoded, psbt);
+        let (decoded, binary) = decode_psbt(&psbt.serialize()).unwrap();
+        assert!(binary);
+        assert_eq!(decoded, psbt);
+    }
+}
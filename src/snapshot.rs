@@ -0,0 +1,518 @@
+//! Bitcoin Core's UTXO set snapshots, as written by `dumptxoutset` for assumeutxo. A snapshot taken
+//! at the parent of a block has every coin the block spends, with its creation height and whether
+//! it comes from a coinbase, so the spent UTXOs can be built without trusting the chain APIs.
+//!
+//! The snapshot starts with its metadata: the `utxo\xff` magic bytes, the version, the network
+//! magic, the hash of the block it was taken at and the number of coins. The coins follow grouped
+//! by txid: the txid, the compact size number of coins and, for each coin, the compact size
+//! output index and the coin as in Core's UTXO database (the `VARINT` of `height * 2 +
+//! coinbase`, followed by the compressed amount and script).
+//!
+//! The snapshot has no block hashes nor headers, which the coin times need. They can be taken
+//! from a headers file (see [read_headers]) instead of the chain APIs, so that no explorer is
+//! trusted nor requested (see [crate::SpentUtxoFetcher::with_local_chain]).
+
+use crate::encoding::{decompress_amount, read_compressed_script, read_varint};
+use crate::FetchError;
+use bitcoin::block::Header;
+use bitcoin::consensus::Decodable;
+use bitcoin::hashes::Hash;
+use bitcoin::p2p::Magic;
+use bitcoin::{Amount, Block, BlockHash, OutPoint, TxOut, Txid};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+/// Magic bytes at the start of a UTXO snapshot.
+pub const SNAPSHOT_MAGIC: &[u8; 5] = b"utxo\xff";
+
+/// The snapshot version written by Bitcoin Core 28 and later, the only one supported.
+pub const SNAPSHOT_VERSION: u16 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The header of a UTXO snapshot.
+pub struct SnapshotMetadata {
+    pub network_magic: Magic,
+    /// The block whose UTXO set the snapshot has, i.e. after applying it
+    pub base_blockhash: BlockHash,
+    pub coins_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// An unspent output in the snapshot.
+pub struct SnapshotCoin {
+    pub txout: TxOut,
+    pub height: u32,
+    pub is_coinbase: bool,
+}
+
+/// The coins of a UTXO snapshot, or the ones selected when reading it, along with the block they
+/// are the UTXO set of.
+pub struct UtxoSnapshot {
+    pub metadata: SnapshotMetadata,
+    tip: BlockHash,
+    coins: HashMap<OutPoint, SnapshotCoin>,
+}
+
+impl UtxoSnapshot {
+    /// Reads the snapshot file, keeping only the coins for which `keep` returns true. A mainnet
+    /// snapshot has well over a hundred million coins, so typically only the ones spent by the
+    /// processed blocks are kept.
+    pub fn read(path: &Path, keep: impl FnMut(&OutPoint) -> bool) -> io::Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?), keep)
+    }
+
+    /// Reads a snapshot, keeping only the coins for which `keep` returns true.
+    pub fn from_reader<R: Read>(
+        mut reader: R,
+        mut keep: impl FnMut(&OutPoint) -> bool,
+    ) -> io::Result<Self> {
+        let metadata = read_metadata(&mut reader)?;
+
+        let mut coins = HashMap::new();
+        let mut remaining = metadata.coins_count;
+        while remaining > 0 {
+            let mut txid = [0u8; 32];
+            reader.read_exact(&mut txid)?;
+            let txid = Txid::from_byte_array(txid);
+
+            let group_size = read_compact_size(&mut reader)?;
+            if group_size == 0 || group_size > remaining {
+                return Err(invalid_data("wrong number of coins"));
+            }
+            for _ in 0..group_size {
+                let vout = u32::try_from(read_compact_size(&mut reader)?)
+                    .map_err(|_| invalid_data("output index is too large"))?;
+                let coin = read_coin(&mut reader)?;
+
+                let outpoint = OutPoint::new(txid, vout);
+                if keep(&outpoint) {
+                    coins.insert(outpoint, coin);
+                }
+            }
+            remaining -= group_size;
+        }
+
+        Ok(UtxoSnapshot {
+            metadata,
+            tip: metadata.base_blockhash,
+            coins,
+        })
+    }
+
+    /// The block whose UTXO set this is: the snapshot base, or the last applied block.
+    pub fn tip(&self) -> BlockHash {
+        self.tip
+    }
+
+    /// Returns the coin at `outpoint`, if it is unspent (and was kept).
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&SnapshotCoin> {
+        self.coins.get(outpoint)
+    }
+
+    /// The number of coins kept.
+    pub fn len(&self) -> usize {
+        self.coins.len()
+    }
+
+    /// Whether no coin was kept.
+    pub fn is_empty(&self) -> bool {
+        self.coins.is_empty()
+    }
+
+    /// Applies the block at `height`, which must build on the tip, so that a snapshot taken
+    /// before the parent of a block can be used for it: the coins it spends are removed, and the
+    /// outputs it creates are added if `keep` returns true for them.
+    pub fn apply_block(
+        &mut self,
+        block: &Block,
+        height: u32,
+        mut keep: impl FnMut(&OutPoint) -> bool,
+    ) -> Result<(), FetchError> {
+        if block.header.prev_blockhash != self.tip {
+            return Err(FetchError::SnapshotMismatch {
+                tip: self.tip,
+                block: block.block_hash(),
+            });
+        }
+
+        for tx in &block.txdata {
+            if !tx.is_coinbase() {
+                for txin in &tx.input {
+                    self.coins.remove(&txin.previous_output);
+                }
+            }
+            let txid = tx.compute_txid();
+            for (vout, txout) in tx.output.iter().enumerate() {
+                let outpoint = OutPoint::new(txid, vout as u32);
+                // Provably unspendable outputs are not part of the UTXO set
+                if txout.script_pubkey.is_op_return() || !keep(&outpoint) {
+                    continue;
+                }
+                let coin = SnapshotCoin {
+                    txout: txout.clone(),
+                    height,
+                    is_coinbase: tx.is_coinbase(),
+                };
+                self.coins.insert(outpoint, coin);
+            }
+        }
+        self.tip = block.block_hash();
+        Ok(())
+    }
+}
+
+/// Reads the snapshot metadata, checking the magic bytes and the version.
+pub fn read_metadata<R: Read>(reader: &mut R) -> io::Result<SnapshotMetadata> {
+    let mut magic = [0u8; 5];
+    reader.read_exact(&mut magic)?;
+    if &magic != SNAPSHOT_MAGIC {
+        return Err(invalid_data(
+            "not a UTXO snapshot (written by Bitcoin Core 28 or later)",
+        ));
+    }
+    let mut version = [0u8; 2];
+    reader.read_exact(&mut version)?;
+    let version = u16::from_le_bytes(version);
+    if version != SNAPSHOT_VERSION {
+        return Err(invalid_data(&format!(
+            "unsupported snapshot version {}",
+            version
+        )));
+    }
+
+    let mut network_magic = [0u8; 4];
+    reader.read_exact(&mut network_magic)?;
+    let mut base_blockhash = [0u8; 32];
+    reader.read_exact(&mut base_blockhash)?;
+    let mut coins_count = [0u8; 8];
+    reader.read_exact(&mut coins_count)?;
+
+    Ok(SnapshotMetadata {
+        network_magic: Magic::from_bytes(network_magic),
+        base_blockhash: BlockHash::from_byte_array(base_blockhash),
+        coins_count: u64::from_le_bytes(coins_count),
+    })
+}
+
+/// Reads a headers file: the 80-byte headers of consecutive blocks from the genesis block,
+/// concatenated, as written by
+/// `bitcoin-cli getblockheader $(bitcoin-cli getblockhash <height>) false | xxd -r -p` for each
+/// height in order.
+pub fn read_headers(path: &Path) -> io::Result<Vec<Header>> {
+    let bytes = std::fs::read(path)?;
+    if bytes.len() % Header::SIZE != 0 {
+        return Err(invalid_data(
+            "the headers file is not a sequence of 80-byte headers",
+        ));
+    }
+    bytes
+        .chunks(Header::SIZE)
+        .map(|mut chunk| {
+            Header::consensus_decode(&mut chunk)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .collect()
+}
+
+/// Reads a coin as serialized in Bitcoin Core's UTXO database.
+fn read_coin<R: Read>(reader: &mut R) -> io::Result<SnapshotCoin> {
+    let code = read_varint(reader)?;
+    let height = u32::try_from(code >> 1).map_err(|_| invalid_data("coin height is too large"))?;
+    let amount = decompress_amount(read_varint(reader)?);
+    let script_pubkey = read_compressed_script(reader)?;
+
+    Ok(SnapshotCoin {
+        txout: TxOut {
+            value: Amount::from_sat(amount),
+            script_pubkey,
+        },
+        height,
+        is_coinbase: code & 1 == 1,
+    })
+}
+
+/// Reads a Bitcoin compact size integer.
+fn read_compact_size<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut prefix = [0u8];
+    reader.read_exact(&mut prefix)?;
+    let size = match prefix[0] {
+        0xFD => 2,
+        0xFE => 4,
+        0xFF => 8,
+        n => return Ok(n as u64),
+    };
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes[..size])?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::{compress_amount, write_compressed_script, write_varint};
+    use crate::mock::MockEsplora;
+    use crate::UtxoData;
+    use bitcoin::block::Header;
+    use bitcoin::consensus::{serialize, Encodable};
+    use bitcoin::constants::genesis_block;
+    use bitcoin::{
+        absolute, transaction, Network, ScriptBuf, Sequence, Transaction, TxIn, Witness,
+    };
+
+    /// Serializes the coins as `dumptxoutset` does, grouped by txid in the given order.
+    fn write_snapshot(
+        base_blockhash: BlockHash,
+        groups: &[(Txid, Vec<(u32, SnapshotCoin)>)],
+    ) -> Vec<u8> {
+        let mut buf = SNAPSHOT_MAGIC.to_vec();
+        buf.extend(SNAPSHOT_VERSION.to_le_bytes());
+        buf.extend(Network::Regtest.magic().to_bytes());
+        buf.extend(base_blockhash.to_byte_array());
+        let coins_count: usize = groups.iter().map(|(_, coins)| coins.len()).sum();
+        buf.extend((coins_count as u64).to_le_bytes());
+
+        for (txid, coins) in groups {
+            buf.extend(txid.to_byte_array());
+            bitcoin::VarInt::from(coins.len())
+                .consensus_encode(&mut buf)
+                .unwrap();
+            for (vout, coin) in coins {
+                bitcoin::VarInt::from(*vout)
+                    .consensus_encode(&mut buf)
+                    .unwrap();
+                write_varint(
+                    &mut buf,
+                    ((coin.height as u64) << 1) | coin.is_coinbase as u64,
+                );
+                write_varint(&mut buf, compress_amount(coin.txout.value.to_sat()));
+                write_compressed_script(&mut buf, &coin.txout.script_pubkey);
+            }
+        }
+        buf
+    }
+
+    #[test]
+    fn test_read_snapshot() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let coinbase = SnapshotCoin {
+            txout: genesis.txdata[0].output[0].clone(),
+            height: 0,
+            is_coinbase: true,
+        };
+        let other = SnapshotCoin {
+            txout: TxOut {
+                value: Amount::from_sat(12_345),
+                script_pubkey: ScriptBuf::from_bytes(vec![0x51]),
+            },
+            height: 300,
+            is_coinbase: false,
+        };
+        let coinbase_txid = genesis.txdata[0].compute_txid();
+        let other_txid = Txid::from_byte_array([1; 32]);
+        let groups = [
+            (coinbase_txid, vec![(0, coinbase.clone())]),
+            (other_txid, vec![(1, other.clone()), (300, other.clone())]),
+        ];
+        let bytes = write_snapshot(genesis.block_hash(), &groups);
+
+        let snapshot = UtxoSnapshot::from_reader(&bytes[..], |_| true).unwrap();
+        assert_eq!(snapshot.metadata.network_magic, Network::Regtest.magic());
+        assert_eq!(snapshot.metadata.coins_count, 3);
+        assert_eq!(snapshot.tip(), genesis.block_hash());
+        assert_eq!(
+            snapshot.get(&OutPoint::new(coinbase_txid, 0)),
+            Some(&coinbase)
+        );
+        assert_eq!(snapshot.get(&OutPoint::new(other_txid, 300)), Some(&other));
+
+        // Only the selected coins are kept
+        let wanted = OutPoint::new(other_txid, 1);
+        let snapshot =
+            UtxoSnapshot::from_reader(&bytes[..], |outpoint| *outpoint == wanted).unwrap();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot.get(&wanted), Some(&other));
+
+        // Truncated or foreign files are rejected
+        assert!(UtxoSnapshot::from_reader(&bytes[..bytes.len() - 1], |_| true).is_err());
+        assert!(UtxoSnapshot::from_reader(&bytes[1..], |_| true).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_backend() {
+        let mock = MockEsplora::start(&[1_000, 2_000, 3_000, 4_000, 5_000]).await;
+        let spend = |outpoints: &[OutPoint]| Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: outpoints
+                .iter()
+                .map(|outpoint| TxIn {
+                    previous_output: *outpoint,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: Amount::from_sat(1_000),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+
+        // A snapshot at block 3, with a coinbase output from block 1 and another output
+        let coinbase_tx = genesis_block(Network::Bitcoin).txdata[0].clone();
+        let coinbase = OutPoint::new(coinbase_tx.compute_txid(), 0);
+        let other = OutPoint::new(Txid::from_byte_array([1; 32]), 0);
+        let coin = |height, is_coinbase| SnapshotCoin {
+            txout: coinbase_tx.output[0].clone(),
+            height,
+            is_coinbase,
+        };
+        let groups = [
+            (coinbase.txid, vec![(0, coin(1, true))]),
+            (other.txid, vec![(0, coin(2, false))]),
+        ];
+        let bytes = write_snapshot(mock.block_hash(3), &groups);
+        let mut snapshot = UtxoSnapshot::from_reader(&bytes[..], |_| true).unwrap();
+
+        // Block 4 spends the other output and creates a new one
+        let block_4 = Block {
+            header: mock.headers[4],
+            txdata: vec![spend(&[OutPoint::null()]), spend(&[other])],
+        };
+        snapshot.apply_block(&block_4, 4, |_| true).unwrap();
+        assert_eq!(snapshot.tip(), mock.block_hash(4));
+        assert!(snapshot.get(&other).is_none());
+
+        let created = OutPoint::new(block_4.txdata[1].compute_txid(), 0);
+        let block_5 = Block {
+            header: Header {
+                prev_blockhash: mock.block_hash(4),
+                time: 6_000,
+                ..mock.headers[4]
+            },
+            txdata: vec![spend(&[OutPoint::null()]), spend(&[coinbase, created])],
+        };
+        let fetcher = mock.fetcher().with_snapshot(snapshot);
+        fetcher.cache_header(5, block_5.header);
+        let utxos = fetcher.fetch_block(&block_5).await.unwrap();
+        assert_eq!(utxos.len(), 2);
+
+        // The coin times are the median times past of blocks 0 and 3
+        let expected = [
+            (coinbase, coinbase_tx.output[0].clone(), true, 1, 1_000),
+            (
+                created,
+                block_4.txdata[1].output[0].clone(),
+                false,
+                4,
+                3_000,
+            ),
+        ];
+        for ((outpoint, utxo), (expected_outpoint, txout, is_coinbase, height, time)) in
+            utxos.into_iter().zip(expected)
+        {
+            assert_eq!(outpoint, expected_outpoint);
+            let expected_utxo = UtxoData {
+                txout,
+                is_coinbase,
+                creation_height: height,
+                creation_time: time,
+            };
+            assert_eq!(utxo, expected_utxo);
+        }
+        // No transaction was looked up
+        let requests = mock.server.received_requests().await.unwrap();
+        assert!(requests.iter().all(|r| !r.url.path().starts_with("/tx/")));
+
+        assert!(matches!(
+            fetcher.fetch_utxo(other).await,
+            Err(FetchError::NotInSnapshot(_))
+        ));
+        // Block 4 doesn't build on the snapshot tip anymore
+        assert!(matches!(
+            fetcher.fetch_block(&block_4).await,
+            Err(FetchError::SnapshotMismatch { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_local_chain() {
+        let mock = MockEsplora::start(&[1_000, 2_000, 3_000, 4_000, 5_000]).await;
+
+        // The headers file round trips, and a partial header is rejected
+        let path = std::env::temp_dir().join(format!("headers_{}.dat", std::process::id()));
+        let bytes: Vec<u8> = mock.headers.iter().flat_map(serialize).collect();
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(read_headers(&path).unwrap(), mock.headers);
+        std::fs::write(&path, &bytes[1..]).unwrap();
+        assert!(read_headers(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        // A snapshot at block 3 with a coinbase output from block 1, spent by block 4
+        let coinbase_tx = genesis_block(Network::Bitcoin).txdata[0].clone();
+        let coinbase = OutPoint::new(coinbase_tx.compute_txid(), 0);
+        let coin = SnapshotCoin {
+            txout: coinbase_tx.output[0].clone(),
+            height: 1,
+            is_coinbase: true,
+        };
+        let bytes = write_snapshot(mock.block_hash(3), &[(coinbase.txid, vec![(0, coin)])]);
+        let snapshot = || UtxoSnapshot::from_reader(&bytes[..], |_| true).unwrap();
+        let spend = |previous_output| Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(1_000),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        let block_4 = Block {
+            header: mock.headers[4],
+            txdata: vec![spend(OutPoint::null()), spend(coinbase)],
+        };
+
+        // The height and coin time come from the headers, without any request
+        let fetcher = mock
+            .fetcher()
+            .with_local_chain(&mock.headers[..4])
+            .unwrap()
+            .with_snapshot(snapshot());
+        let utxos = fetcher.fetch_block(&block_4).await.unwrap();
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].1.creation_height, 1);
+        assert_eq!(utxos[0].1.creation_time, 1_000);
+        assert!(matches!(
+            fetcher
+                .fetch_transaction(block_4.txdata[1].compute_txid())
+                .await,
+            Err(FetchError::Offline(_))
+        ));
+        assert_eq!(mock.request_count().await, 0);
+
+        // The snapshot base must be in the headers
+        let fetcher = mock
+            .fetcher()
+            .with_local_chain(&mock.headers[..3])
+            .unwrap()
+            .with_snapshot(snapshot());
+        assert!(matches!(
+            fetcher.fetch_block(&block_4).await,
+            Err(FetchError::NotInLocalChain(_))
+        ));
+
+        // The headers must chain from a genesis block
+        assert!(mock.fetcher().with_local_chain(&mock.headers[1..]).is_err());
+        let unchained = [mock.headers[0], mock.headers[2]];
+        assert!(mock.fetcher().with_local_chain(&unchained).is_err());
+    }
+}
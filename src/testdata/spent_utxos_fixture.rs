@@ -0,0 +1,32 @@
+// Spent UTXOs of block 0000000000000000000000000000000000000000000000000000000000000000
+// Generated by utxo_fetcher, do not edit manually.
+
+/// (txid, vout, value, script_pubkey, is_coinbase, creation_height, creation_time)
+const SPENT_UTXOS: &[(&str, u32, u64, &str, bool, u32, u32)] = &[
+    ("0000000000000000000000000000000000000000000000000000000000000000", 0, 1000, "51", true, 800000, 1690000000),
+    ("0000000000000000000000000000000000000000000000000000000000000000", 1, 1001, "51", false, 800000, 1690000000),
+    ("0000000000000000000000000000000000000000000000000000000000000000", 2, 1002, "51", false, 800000, 1690000000),
+];
+
+/// Returns the UTXOs spent by the block, keyed by the outpoint that references them.
+fn spent_utxos() -> ::std::collections::HashMap<::bitcoin::OutPoint, UtxoData> {
+    use ::bitcoin::{Amount, OutPoint, ScriptBuf, TxOut, Txid};
+    use ::std::str::FromStr;
+
+    SPENT_UTXOS
+        .iter()
+        .map(|&(txid, vout, value, script, is_coinbase, creation_height, creation_time)| {
+            let outpoint = OutPoint::new(Txid::from_str(txid).unwrap(), vout);
+            let utxo = UtxoData {
+                txout: TxOut {
+                    value: Amount::from_sat(value),
+                    script_pubkey: ScriptBuf::from_hex(script).unwrap(),
+                },
+                is_coinbase,
+                creation_height,
+                creation_time,
+            };
+            (outpoint, utxo)
+        })
+        .collect()
+}
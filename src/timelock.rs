@@ -0,0 +1,210 @@
+//! Evaluation of the timelocks of a spending transaction: for every input, the earliest block in
+//! which its BIP 68 relative lock and the transaction `lock_time` are satisfied, computed from the
+//! `creation_height` and `creation_time` of the spent outputs.
+//!
+//! The rules are the ones active since the CSV soft fork, where time-based locks are compared
+//! against the median time past (MTP) of the block preceding the one including the transaction.
+
+use crate::error::ValidationError;
+use crate::UtxoData;
+use bitcoin::absolute::LOCK_TIME_THRESHOLD;
+use bitcoin::{relative, Sequence, Transaction};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+/// The earliest block that satisfies a lock: its lowest height, and the lowest MTP of its previous
+/// block. A zero means that there is no requirement.
+pub struct LockRequirement {
+    pub min_height: u32,
+    pub min_mtp: u32,
+}
+
+impl LockRequirement {
+    /// Whether a block at `height`, whose previous block has the given `mtp`, satisfies the lock.
+    pub fn is_satisfied(&self, height: u32, mtp: u32) -> bool {
+        height >= self.min_height && mtp >= self.min_mtp
+    }
+
+    /// The requirement of satisfying both locks.
+    pub fn max(self, other: LockRequirement) -> LockRequirement {
+        LockRequirement {
+            min_height: self.min_height.max(other.min_height),
+            min_mtp: self.min_mtp.max(other.min_mtp),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// The timelocks that apply to one input of a transaction.
+pub struct InputTimelock {
+    pub vin: usize,
+    pub sequence: Sequence,
+    /// The BIP 68 relative lock of the input.
+    pub sequence_lock: LockRequirement,
+    /// The transaction `lock_time`, which is the same for every input.
+    pub lock_time: LockRequirement,
+}
+
+impl InputTimelock {
+    /// The earliest block that satisfies both locks.
+    pub fn earliest(&self) -> LockRequirement {
+        self.sequence_lock.max(self.lock_time)
+    }
+
+    /// Whether a block at `height`, whose previous block has the given `mtp`, satisfies both locks.
+    pub fn is_satisfied(&self, height: u32, mtp: u32) -> bool {
+        self.earliest().is_satisfied(height, mtp)
+    }
+}
+
+/// Returns the timelocks of every input of the transaction, given the UTXOs they spend in input
+/// order. Relative locks only apply from version 2, with the version read as unsigned as Core
+/// does, so negative versions have them too.
+///
+/// Outputs of unconfirmed transactions have no creation height nor time yet, so the relative
+/// locks that spend them get the [crate::UNCONFIRMED_HEIGHT] and [crate::UNCONFIRMED_TIME]
+/// placeholders (or higher) as requirements.
+pub fn evaluate_timelocks(
+    tx: &Transaction,
+    spent_utxos: &[UtxoData],
+) -> Result<Vec<InputTimelock>, ValidationError> {
+    if tx.input.len() != spent_utxos.len() {
+        return Err(ValidationError::InputCountMismatch {
+            inputs: tx.input.len(),
+            utxos: spent_utxos.len(),
+        });
+    }
+
+    let lock_time = lock_time_requirement(tx);
+    let timelocks = tx
+        .input
+        .iter()
+        .zip(spent_utxos)
+        .enumerate()
+        .map(|(vin, (txin, utxo))| InputTimelock {
+            vin,
+            sequence: txin.sequence,
+            sequence_lock: match tx.version.0 as u32 >= 2 {
+                true => sequence_lock_requirement(txin.sequence, utxo),
+                false => LockRequirement::default(),
+            },
+            lock_time,
+        })
+        .collect();
+    Ok(timelocks)
+}
+
+/// Returns the requirement of the transaction `lock_time`, as Bitcoin Core's `IsFinalTx`: a block
+/// height lock `n` is satisfied above height `n`, and a time lock `t` once the MTP is above `t`.
+/// A lock time is ignored if all the inputs have a final sequence.
+pub fn lock_time_requirement(tx: &Transaction) -> LockRequirement {
+    let lock_time = tx.lock_time.to_consensus_u32();
+    if lock_time == 0 || tx.input.iter().all(|txin| txin.sequence == Sequence::MAX) {
+        return LockRequirement::default();
+    }
+
+    if lock_time < LOCK_TIME_THRESHOLD {
+        LockRequirement {
+            min_height: lock_time + 1,
+            min_mtp: 0,
+        }
+    } else {
+        LockRequirement {
+            min_height: 0,
+            min_mtp: lock_time.saturating_add(1),
+        }
+    }
+}
+
+/// Returns the requirement of the BIP 68 relative lock of an input spending `utxo`, as Bitcoin
+/// Core's `CalculateSequenceLocks`: a lock of `n` blocks is satisfied from `n` blocks above the
+/// creation height, and a lock of `n` 512-second intervals once the MTP reaches the creation time
+/// plus `n * 512`.
+pub fn sequence_lock_requirement(sequence: Sequence, utxo: &UtxoData) -> LockRequirement {
+    match sequence.to_relative_lock_time() {
+        // The disable flag is set, so there is no relative lock
+        None => LockRequirement::default(),
+        Some(relative::LockTime::Blocks(blocks)) => LockRequirement {
+            min_height: utxo.creation_height.saturating_add(blocks.value() as u32),
+            min_mtp: 0,
+        },
+        Some(relative::LockTime::Time(time)) => LockRequirement {
+            min_height: 0,
+            // Each time unit is 512 seconds
+            min_mtp: utxo
+                .creation_time
+                .saturating_add((time.value() as u32) << 9),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{transaction, Amount, OutPoint, ScriptBuf, TxIn, TxOut, Txid, Witness};
+
+    #[test]
+    fn test_evaluate_timelocks() {
+        let txin = |sequence| TxIn {
+            previous_output: OutPoint::new(Txid::all_zeros(), 0),
+            script_sig: ScriptBuf::new(),
+            sequence,
+            witness: Witness::new(),
+        };
+        let mut tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::from_height(700_000).unwrap(),
+            input: vec![
+                txin(Sequence::from_height(10)),
+                txin(Sequence::from_512_second_intervals(2)),
+                txin(Sequence::ENABLE_LOCKTIME_NO_RBF),
+            ],
+            output: vec![],
+        };
+        let utxo = UtxoData {
+            txout: TxOut {
+                value: Amount::from_sat(1_000),
+                script_pubkey: ScriptBuf::new(),
+            },
+            is_coinbase: false,
+            creation_height: 699_995,
+            creation_time: 1_600_000_000,
+        };
+        let utxos = [utxo.clone(), utxo.clone(), utxo];
+
+        let timelocks = evaluate_timelocks(&tx, &utxos).unwrap();
+        let lock_time = LockRequirement {
+            min_height: 700_001,
+            min_mtp: 0,
+        };
+        assert_eq!(timelocks[0].lock_time, lock_time);
+        assert_eq!(timelocks[0].sequence_lock.min_height, 700_005);
+        assert_eq!(timelocks[0].earliest().min_height, 700_005);
+        assert_eq!(timelocks[1].sequence_lock.min_mtp, 1_600_001_024);
+        assert_eq!(timelocks[2].sequence_lock, LockRequirement::default());
+
+        // Each lock is satisfied exactly from its earliest block
+        assert!(timelocks[0].is_satisfied(700_005, 0));
+        assert!(!timelocks[0].is_satisfied(700_004, 0));
+        assert!(timelocks[1].is_satisfied(700_001, 1_600_001_024));
+        assert!(!timelocks[1].is_satisfied(700_001, 1_600_001_023));
+        assert!(!timelocks[2].is_satisfied(700_000, u32::MAX));
+
+        // Negative versions are above 2 as unsigned
+        tx.version = transaction::Version(-1);
+        let negative = evaluate_timelocks(&tx, &utxos).unwrap();
+        assert_eq!(negative[0].sequence_lock.min_height, 700_005);
+
+        // Version 1 transactions have no relative locks, and final sequences disable lock_time
+        tx.version = transaction::Version::ONE;
+        tx.input
+            .iter_mut()
+            .for_each(|txin| txin.sequence = Sequence::MAX);
+        let timelocks = evaluate_timelocks(&tx, &utxos).unwrap();
+        assert!(timelocks.iter().all(|lock| lock.is_satisfied(0, 0)));
+
+        assert!(evaluate_timelocks(&tx, &utxos[1..]).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+//! Bitcoin Core's block undo data (`CBlockUndo`), which is how Core stores the outputs spent by
+//! each block in its `rev*.dat` files.
+
+use crate::encoding::{compress_amount, write_compressed_script, write_varint};
+use crate::error::ValidationError;
+use crate::UtxoData;
+use bitcoin::consensus::Encodable;
+use bitcoin::Block;
+
+/// Serializes the spent UTXOs of the block as a `CBlockUndo`, byte for byte as Bitcoin Core does.
+///
+/// The `spent_utxos` must be in input order. The result is a compact size count of `CTxUndo`s,
+/// one per non-coinbase transaction, each with a compact size count of spent coins in input order.
+/// A coin is written as:
+///
+/// - The `VARINT` of `creation_height * 2 + is_coinbase`.
+/// - If the height is not zero, a zero byte that older Core versions used for the transaction
+///   version.
+/// - The `VARINT` of the compressed amount.
+/// - The compressed script.
+///
+/// In `rev*.dat` files each `CBlockUndo` is preceded by the network magic and its size, and
+/// followed by a checksum, which are not included here.
+pub fn encode_block_undo(
+    block: &Block,
+    spent_utxos: &[UtxoData],
+) -> Result<Vec<u8>, ValidationError> {
+    let total_inputs: usize = block.txdata[1..].iter().map(|tx| tx.input.len()).sum();
+    if total_inputs != spent_utxos.len() {
+        return Err(ValidationError::InputCountMismatch {
+            inputs: total_inputs,
+            utxos: spent_utxos.len(),
+        });
+    }
+
+    let mut buf = Vec::with_capacity(spent_utxos.len() * 32);
+    let mut utxos = spent_utxos.iter();
+
+    // Writing to a vector cannot fail
+    bitcoin::VarInt::from(block.txdata.len() - 1)
+        .consensus_encode(&mut buf)
+        .unwrap();
+    for tx in &block.txdata[1..] {
+        bitcoin::VarInt::from(tx.input.len())
+            .consensus_encode(&mut buf)
+            .unwrap();
+        for utxo in utxos.by_ref().take(tx.input.len()) {
+            let code = ((utxo.creation_height as u64) << 1) | utxo.is_coinbase as u64;
+            write_varint(&mut buf, code);
+            if utxo.creation_height > 0 {
+                buf.push(0);
+            }
+            write_varint(&mut buf, compress_amount(utxo.txout.value.to_sat()));
+            write_compressed_script(&mut buf, &utxo.txout.script_pubkey);
+        }
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::constants::genesis_block;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Amount, Network, OutPoint, ScriptBuf, ScriptHash, TxIn, TxOut};
+
+    #[test]
+    fn test_block_undo() {
+        let mut block = genesis_block(Network::Bitcoin);
+        let coinbase = block.txdata[0].clone();
+        let mut tx = coinbase.clone();
+        tx.input = vec![TxIn {
+            previous_output: OutPoint::new(coinbase.compute_txid(), 0),
+            ..coinbase.input[0].clone()
+        }];
+        let mut tx2 = tx.clone();
+        tx2.input.push(tx2.input[0].clone());
+        block.txdata = vec![coinbase, tx, tx2];
+
+        let utxo = |value, script_pubkey, is_coinbase, creation_height| UtxoData {
+            txout: TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey,
+            },
+            is_coinbase,
+            creation_height,
+            creation_time: 0,
+        };
+        let p2sh = ScriptBuf::new_p2sh(&ScriptHash::all_zeros());
+        let utxos = [
+            utxo(5_000_000_000, p2sh, false, 100),
+            utxo(0, ScriptBuf::new(), true, 0),
+            utxo(1, ScriptBuf::new(), false, 1),
+        ];
+
+        let undo = encode_block_undo(&block, &utxos).unwrap();
+        let mut expected = vec![0x02, 0x01];
+        // Height 100 (a two byte VARINT), the dummy byte, 50 BTC and the P2SH hash
+        expected.extend([0x80, 0x48, 0x00, 0x32, 0x01]);
+        expected.extend([0; 20]);
+        // The coinbase at height 0 has no dummy byte, then an empty script (size 0 plus 6)
+        expected.extend([0x02, 0x01, 0x00, 0x06]);
+        expected.extend([0x02, 0x00, 0x01, 0x06]);
+        assert_eq!(undo, expected);
+
+        assert!(encode_block_undo(&block, &utxos[..2]).is_err());
+    }
+}
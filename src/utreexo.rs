@@ -0,0 +1,138 @@
+//! Utreexo leaf data of the spent UTXOs, as used by Floresta to validate blocks: each spent
+//! output is a leaf of the accumulator, committed to by the hash of its [LeafData].
+
+use crate::UtxoData;
+use bitcoin::consensus::Encodable;
+use bitcoin::hashes::{sha512, sha512_256, Hash, HashEngine};
+use bitcoin::{BlockHash, OutPoint, TxOut};
+use serde::{Deserialize, Serialize};
+
+/// The string whose SHA-512 tags the leaf hashes.
+const UTREEXO_TAG_V1: &[u8] = b"UtreexoV1";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// The data committed to by a Utreexo leaf.
+pub struct LeafData {
+    /// The hash of the block that created the output.
+    pub block_hash: BlockHash,
+    /// The outpoint of the output.
+    pub prevout: OutPoint,
+    /// `creation_height * 2 + is_coinbase`, as in Bitcoin Core's coin serialization.
+    pub header_code: u32,
+    /// The output itself.
+    pub utxo: TxOut,
+}
+
+impl LeafData {
+    /// Builds the leaf data of the output at `prevout`, created in the block with `block_hash`.
+    pub fn new(block_hash: BlockHash, prevout: OutPoint, utxo: &UtxoData) -> Self {
+        LeafData {
+            block_hash,
+            prevout,
+            header_code: (utxo.creation_height << 1) | utxo.is_coinbase as u32,
+            utxo: utxo.txout.clone(),
+        }
+    }
+
+    /// The consensus serialization: block hash, outpoint, little-endian header code and `TxOut`.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(80 + self.utxo.script_pubkey.len());
+        // Writing to a vector cannot fail
+        self.block_hash.consensus_encode(&mut buf).unwrap();
+        self.prevout.consensus_encode(&mut buf).unwrap();
+        self.header_code.consensus_encode(&mut buf).unwrap();
+        self.utxo.consensus_encode(&mut buf).unwrap();
+        buf
+    }
+
+    /// The leaf hash: SHA-512/256 of the serialization, prefixed twice with the SHA-512 of
+    /// "UtreexoV1".
+    pub fn leaf_hash(&self) -> sha512_256::Hash {
+        let tag = sha512::Hash::hash(UTREEXO_TAG_V1);
+
+        let mut engine = sha512_256::Hash::engine();
+        engine.input(tag.as_byte_array());
+        engine.input(tag.as_byte_array());
+        engine.input(&self.serialize());
+        sha512_256::Hash::from_engine(engine)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// A spent output's leaf data along with its hash, as written to the leaves file.
+pub struct UtreexoLeaf {
+    pub leaf_hash: sha512_256::Hash,
+    #[serde(flatten)]
+    pub data: LeafData,
+}
+
+impl From<LeafData> for UtreexoLeaf {
+    fn from(data: LeafData) -> Self {
+        UtreexoLeaf {
+            leaf_hash: data.leaf_hash(),
+            data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hex::DisplayHex;
+    use bitcoin::{Amount, ScriptBuf};
+
+    #[test]
+    fn test_leaf_hash() {
+        // The 50 BTC P2PK output of the genesis coinbase
+        let genesis = bitcoin::constants::genesis_block(bitcoin::Network::Bitcoin);
+        let coinbase = &genesis.txdata[0];
+        let utxo = UtxoData {
+            txout: coinbase.output[0].clone(),
+            is_coinbase: true,
+            creation_height: 0,
+            creation_time: 0,
+        };
+        assert_eq!(utxo.txout.value, Amount::from_int_btc(50));
+        let prevout = OutPoint::new(coinbase.compute_txid(), 0);
+        let leaf = LeafData::new(genesis.block_hash(), prevout, &utxo);
+        assert_eq!(leaf.header_code, 1);
+
+        // 32 bytes of block hash, 36 of outpoint, 4 of header code and 76 of TxOut
+        let serialized = leaf.serialize();
+        assert_eq!(serialized.len(), 148);
+        assert_eq!(serialized[68..72], 1u32.to_le_bytes());
+
+        // Computed separately with Python's hashlib, as SHA-512/256 of the double SHA-512 tag of
+        // "UtreexoV1" followed by the serialization above
+        assert_eq!(
+            leaf.leaf_hash().to_byte_array().to_lower_hex_string(),
+            "48301b301aca2a7c592b28aebd0f7cd1b6d9f1bfa76bd062b1b100ae10b8c3a7"
+        );
+
+        // The hash commits to every field
+        let leaves = [
+            LeafData {
+                block_hash: BlockHash::all_zeros(),
+                ..leaf.clone()
+            },
+            LeafData {
+                header_code: 0,
+                ..leaf.clone()
+            },
+            LeafData {
+                utxo: TxOut {
+                    script_pubkey: ScriptBuf::new(),
+                    ..leaf.utxo.clone()
+                },
+                ..leaf.clone()
+            },
+        ];
+        for other in leaves {
+            assert_ne!(leaf.leaf_hash(), other.leaf_hash());
+        }
+
+        let json = serde_json::to_value(UtreexoLeaf::from(leaf)).unwrap();
+        assert_eq!(json["header_code"], 1);
+        assert!(json["leaf_hash"].is_string());
+    }
+}
@@ -0,0 +1,469 @@
+use crate::error::ValidationError;
+use crate::format::KeyedUtxos;
+use crate::timelock::{lock_time_requirement, sequence_lock_requirement};
+use crate::UtxoData;
+use bitcoin::{Amount, Block, Network, Txid};
+use serde::{Deserialize, Serialize};
+
+/// Number of confirmations a coinbase output needs before it can be spent.
+pub const COINBASE_MATURITY: u32 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The consensus parameters of a network used by the checks, as in Bitcoin Core's chainparams.
+pub struct ChainParams {
+    /// Height at which BIP 34 (block height in the coinbase) was enforced.
+    pub bip34_height: u32,
+    /// Height at which the CSV soft fork (BIPs 68, 112 and 113) was activated.
+    pub csv_height: u32,
+    /// Number of blocks between subsidy halvings.
+    pub halving_interval: u32,
+}
+
+impl ChainParams {
+    pub const MAINNET: ChainParams = ChainParams {
+        bip34_height: 227_931,
+        csv_height: 419_328,
+        halving_interval: 210_000,
+    };
+
+    pub const TESTNET3: ChainParams = ChainParams {
+        bip34_height: 21_111,
+        csv_height: 770_112,
+        halving_interval: 210_000,
+    };
+
+    /// Testnet4 and signet, which enforce every soft fork from the start.
+    pub const TESTNET4: ChainParams = ChainParams {
+        bip34_height: 1,
+        csv_height: 1,
+        halving_interval: 210_000,
+    };
+
+    /// Regtest without `-testactivationheight`, where the subsidy halves every 150 blocks.
+    pub const REGTEST: ChainParams = ChainParams {
+        bip34_height: 1,
+        csv_height: 1,
+        halving_interval: 150,
+    };
+}
+
+impl From<Network> for ChainParams {
+    fn from(network: Network) -> Self {
+        match network {
+            Network::Bitcoin => ChainParams::MAINNET,
+            Network::Testnet => ChainParams::TESTNET3,
+            Network::Testnet4 | Network::Signet => ChainParams::TESTNET4,
+            Network::Regtest => ChainParams::REGTEST,
+        }
+    }
+}
+
+/// The mainnet coinbase transactions that were repeated before BIP 30, with the heights of both
+/// instances. The outputs of the second instance overwrote those of the first, which were never
+/// spent, so these are the only transactions that can't be identified by their txid alone.
+pub const DUPLICATE_COINBASES: [(&str, [u32; 2]); 2] = [
+    (
+        "e3bf3d07d4b0375638d5f1db5255fe07ba2c4cb067cd81b84ee974b6585fb468",
+        [91_722, 91_880],
+    ),
+    (
+        "d5d27987d2a3dfc724e359870c6644b40e497bdc0589a033220fe15429d88599",
+        [91_812, 91_842],
+    ),
+];
+
+/// Returns the heights of the instances of a duplicate coinbase txid, or `None` if the txid is
+/// unique.
+pub fn duplicate_coinbase_heights(txid: &Txid) -> Option<[u32; 2]> {
+    let txid = txid.to_string();
+    DUPLICATE_COINBASES
+        .iter()
+        .find(|(duplicate, _)| *duplicate == txid)
+        .map(|(_, heights)| *heights)
+}
+
+/// Returns the height of the instance of a duplicate txid that is unspent when a block at
+/// `spend_height` is validated: the last one created below that height, as every new instance
+/// overwrites the previous one. Returns `None` if all instances are at or above `spend_height`.
+pub fn spendable_instance(heights: &[u32], spend_height: u32) -> Option<u32> {
+    heights
+        .iter()
+        .copied()
+        .filter(|height| *height < spend_height)
+        .max()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// The fee paid by a single (non-coinbase) transaction of the block.
+pub struct TxFee {
+    /// The transaction id.
+    pub txid: Txid,
+    /// Sum of the spent output values minus the sum of the created output values.
+    pub fee: Amount,
+}
+
+/// Returns the block subsidy for the given height, halving every `params.halving_interval` blocks.
+pub fn block_subsidy(height: u32, params: &ChainParams) -> Amount {
+    let halvings = height / params.halving_interval;
+    // After 64 halvings the subsidy would be shifted to zero (and the shift would overflow)
+    if halvings >= 64 {
+        return Amount::ZERO;
+    }
+    Amount::from_sat(Amount::from_int_btc(50).to_sat() >> halvings)
+}
+
+/// Computes the fee of every non-coinbase transaction in the block.
+///
+/// The `spent_utxos` must be in input order, i.e. the order in which the block inputs (excluding
+/// the coinbase) reference them. A transaction that creates more value than it spends is reported
+/// as a [ValidationError::NegativeFee], as this means the fetched amounts are wrong.
+pub fn compute_fees(
+    block: &Block,
+    spent_utxos: &[UtxoData],
+) -> Result<Vec<TxFee>, ValidationError> {
+    let total_inputs: usize = block.txdata[1..].iter().map(|tx| tx.input.len()).sum();
+    if total_inputs != spent_utxos.len() {
+        return Err(ValidationError::InputCountMismatch {
+            inputs: total_inputs,
+            utxos: spent_utxos.len(),
+        });
+    }
+
+    let mut utxos = spent_utxos.iter();
+    let mut fees = Vec::with_capacity(block.txdata.len() - 1);
+
+    for tx in &block.txdata[1..] {
+        // Take as many UTXOs as the transaction has inputs
+        let input_value: u64 = utxos
+            .by_ref()
+            .take(tx.input.len())
+            .map(|utxo| utxo.txout.value.to_sat())
+            .sum();
+        let output_value: u64 = tx.output.iter().map(|txout| txout.value.to_sat()).sum();
+
+        let fee = input_value
+            .checked_sub(output_value)
+            .ok_or(ValidationError::NegativeFee {
+                txid: tx.compute_txid(),
+                input_value: Amount::from_sat(input_value),
+                output_value: Amount::from_sat(output_value),
+            })?;
+
+        fees.push(TxFee {
+            txid: tx.compute_txid(),
+            fee: Amount::from_sat(fee),
+        });
+    }
+
+    Ok(fees)
+}
+
+/// Checks that the coinbase transaction claims no more than the block subsidy plus the total fees.
+pub fn check_coinbase_claim(
+    block: &Block,
+    height: u32,
+    fees: &[TxFee],
+    params: &ChainParams,
+) -> Result<(), ValidationError> {
+    let total_fees: u64 = fees.iter().map(|tx_fee| tx_fee.fee.to_sat()).sum();
+    let allowed = block_subsidy(height, params).to_sat() + total_fees;
+    let claimed: u64 = block.txdata[0]
+        .output
+        .iter()
+        .map(|txout| txout.value.to_sat())
+        .sum();
+
+    if claimed > allowed {
+        return Err(ValidationError::CoinbaseOverclaim {
+            height,
+            claimed: Amount::from_sat(claimed),
+            allowed: Amount::from_sat(allowed),
+        });
+    }
+    Ok(())
+}
+
+/// Checks that the outpoints of the keyed UTXOs are, in order, the ones referenced by the block's
+/// non-coinbase inputs.
+pub fn check_outpoints(block: &Block, keyed: &KeyedUtxos) -> Result<(), ValidationError> {
+    let total_inputs: usize = block.txdata[1..].iter().map(|tx| tx.input.len()).sum();
+    if total_inputs != keyed.0.len() {
+        return Err(ValidationError::InputCountMismatch {
+            inputs: total_inputs,
+            utxos: keyed.0.len(),
+        });
+    }
+
+    let inputs = block.txdata[1..].iter().flat_map(|tx| &tx.input);
+    for (index, (txin, (outpoint, _))) in inputs.zip(&keyed.0).enumerate() {
+        if txin.previous_output != *outpoint {
+            return Err(ValidationError::OutpointMismatch {
+                index,
+                expected: txin.previous_output,
+                found: *outpoint,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Checks the coinbase maturity and the timelocks of every non-coinbase input in the block.
+///
+/// The `spent_utxos` must be in input order. The `mtp` is the median time past of the previous
+/// block, which BIP 113 uses for the `lock_time` check and BIP 68 for time-based sequence locks.
+/// Both rules are only enforced from the CSV activation height, as consensus does. Returns every
+/// violation found, reported per input (or per transaction for the `lock_time`).
+pub fn check_input_locks(
+    block: &Block,
+    height: u32,
+    mtp: u32,
+    spent_utxos: &[UtxoData],
+    params: &ChainParams,
+) -> Vec<ValidationError> {
+    let mut violations = Vec::new();
+    let mut utxos = spent_utxos.iter();
+    let csv_active = height >= params.csv_height;
+
+    for tx in &block.txdata[1..] {
+        let txid = tx.compute_txid();
+
+        // Before BIP 113 the lock time was compared against the block's own timestamp
+        let cutoff_time = if csv_active { mtp } else { block.header.time };
+        if !lock_time_requirement(tx).is_satisfied(height, cutoff_time) {
+            violations.push(ValidationError::LockTime {
+                txid,
+                lock_time: tx.lock_time,
+            });
+        }
+
+        for (vin, (txin, utxo)) in tx.input.iter().zip(utxos.by_ref()).enumerate() {
+            if utxo.is_coinbase && height.saturating_sub(utxo.creation_height) < COINBASE_MATURITY {
+                violations.push(ValidationError::ImmatureCoinbaseSpend {
+                    txid,
+                    vin,
+                    creation_height: utxo.creation_height,
+                });
+            }
+
+            // BIP 68 relative lock times apply from version 2, compared as unsigned like Core does
+            if csv_active
+                && tx.version.0 as u32 >= 2
+                && !sequence_lock_requirement(txin.sequence, utxo).is_satisfied(height, mtp)
+            {
+                violations.push(ValidationError::SequenceLock {
+                    txid,
+                    vin,
+                    sequence: txin.sequence,
+                });
+            }
+        }
+    }
+
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::block::{Header, Version};
+    use bitcoin::hashes::Hash;
+    use bitcoin::transaction;
+    use bitcoin::{
+        BlockHash, CompactTarget, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxMerkleNode,
+        TxOut, Witness,
+    };
+
+    fn tx(inputs: usize, outputs: &[u64]) -> Transaction {
+        Transaction {
+            version: transaction::Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: (0..inputs)
+                .map(|vout| TxIn {
+                    previous_output: OutPoint::new(Txid::all_zeros(), vout as u32),
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|value| TxOut {
+                    value: Amount::from_sat(*value),
+                    script_pubkey: ScriptBuf::new(),
+                })
+                .collect(),
+        }
+    }
+
+    fn utxo(value: u64) -> UtxoData {
+        UtxoData {
+            txout: TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: ScriptBuf::new(),
+            },
+            is_coinbase: false,
+            creation_height: 100,
+            creation_time: 0,
+        }
+    }
+
+    fn block(txdata: Vec<Transaction>) -> Block {
+        Block {
+            header: Header {
+                version: Version::ONE,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0),
+                nonce: 0,
+            },
+            txdata,
+        }
+    }
+
+    #[test]
+    fn test_duplicate_coinbases() {
+        let txid: Txid = DUPLICATE_COINBASES[1].0.parse().unwrap();
+        let heights = duplicate_coinbase_heights(&txid).unwrap();
+        assert_eq!(heights, [91_812, 91_842]);
+        assert!(duplicate_coinbase_heights(&Txid::all_zeros()).is_none());
+
+        assert_eq!(spendable_instance(&heights, 91_812), None);
+        assert_eq!(spendable_instance(&heights, 91_842), Some(91_812));
+        assert_eq!(spendable_instance(&heights, 91_843), Some(91_842));
+        assert_eq!(spendable_instance(&heights, 900_000), Some(91_842));
+    }
+
+    #[test]
+    fn test_block_subsidy() {
+        let mainnet = ChainParams::from(Network::Bitcoin);
+        assert_eq!(block_subsidy(0, &mainnet), Amount::from_int_btc(50));
+        assert_eq!(block_subsidy(209_999, &mainnet), Amount::from_int_btc(50));
+        assert_eq!(block_subsidy(210_000, &mainnet), Amount::from_int_btc(25));
+        assert_eq!(
+            block_subsidy(840_000, &mainnet),
+            Amount::from_sat(312_500_000)
+        );
+        assert_eq!(block_subsidy(64 * 210_000, &mainnet), Amount::ZERO);
+
+        // Signet has the mainnet schedule, while regtest halves every 150 blocks
+        let signet = ChainParams::from(Network::Signet);
+        assert_eq!(block_subsidy(210_000, &signet), Amount::from_int_btc(25));
+        let regtest = ChainParams::from(Network::Regtest);
+        assert_eq!(block_subsidy(149, &regtest), Amount::from_int_btc(50));
+        assert_eq!(
+            block_subsidy(300, &regtest),
+            Amount::from_sat(1_250_000_000)
+        );
+    }
+
+    #[test]
+    fn test_fees_and_coinbase_claim() {
+        let params = ChainParams::MAINNET;
+        let subsidy = block_subsidy(500_000, &params).to_sat();
+        let block = block(vec![
+            tx(1, &[subsidy + 1_500]),
+            tx(2, &[2_000, 500]),
+            tx(1, &[9_000]),
+        ]);
+        let utxos = [utxo(1_000), utxo(2_000), utxo(10_000)];
+
+        let fees = compute_fees(&block, &utxos).unwrap();
+        let fees: Vec<_> = fees.iter().map(|tx_fee| tx_fee.fee.to_sat()).collect();
+        assert_eq!(fees, [500, 1_000]);
+
+        let fees = compute_fees(&block, &utxos).unwrap();
+        assert!(check_coinbase_claim(&block, 500_000, &fees, &params).is_ok());
+        // The next halving leaves the coinbase claiming more than allowed
+        assert!(matches!(
+            check_coinbase_claim(&block, 630_000, &fees, &params),
+            Err(ValidationError::CoinbaseOverclaim { .. })
+        ));
+
+        // The first transaction now spends less than it creates
+        let utxos = [utxo(1_000), utxo(1_000), utxo(10_000)];
+        assert!(matches!(
+            compute_fees(&block, &utxos),
+            Err(ValidationError::NegativeFee { .. })
+        ));
+        assert!(matches!(
+            compute_fees(&block, &utxos[1..]),
+            Err(ValidationError::InputCountMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_input_locks() {
+        let params = ChainParams::MAINNET;
+        let height = 500_000;
+        let mtp = 1_500_000_000;
+
+        let mut spend = tx(3, &[1_000]);
+        spend.version = transaction::Version::TWO;
+        // Requires 10 blocks since the UTXO creation
+        spend.input[0].sequence = Sequence::from_height(10);
+        // Requires 10 * 512 seconds since the UTXO creation time
+        spend.input[1].sequence = Sequence::from_512_second_intervals(10);
+        spend.lock_time = LockTime::from_height(height - 1).unwrap();
+        let block = block(vec![tx(1, &[0]), spend]);
+
+        let mut coinbase_utxo = utxo(1_000);
+        coinbase_utxo.is_coinbase = true;
+        coinbase_utxo.creation_height = height - COINBASE_MATURITY;
+
+        let mut utxos = [utxo(1_000), utxo(1_000), coinbase_utxo];
+        utxos[0].creation_height = height - 10;
+        utxos[1].creation_time = mtp - 10 * 512;
+        assert!(check_input_locks(&block, height, mtp, &utxos, &params).is_empty());
+
+        // Make every input one block or one second short of its lock
+        utxos[0].creation_height += 1;
+        utxos[1].creation_time += 1;
+        utxos[2].creation_height += 1;
+        let violations = check_input_locks(&block, height, mtp, &utxos, &params);
+        assert!(matches!(
+            violations[..],
+            [
+                ValidationError::SequenceLock { vin: 0, .. },
+                ValidationError::SequenceLock { vin: 1, .. },
+                ValidationError::ImmatureCoinbaseSpend { vin: 2, .. },
+            ]
+        ));
+
+        // Negative versions are above 2 as unsigned, so the relative locks still apply
+        let mut negative = block.clone();
+        negative.txdata[1].version = transaction::Version(-1);
+        let violations = check_input_locks(&negative, height, mtp, &utxos, &params);
+        assert!(matches!(
+            violations[..],
+            [
+                ValidationError::SequenceLock { vin: 0, .. },
+                ValidationError::SequenceLock { vin: 1, .. },
+                ValidationError::ImmatureCoinbaseSpend { vin: 2, .. },
+            ]
+        ));
+
+        // The lock time is not final at the block height
+        let violations = check_input_locks(&block, height - 1, mtp, &utxos, &params);
+        assert!(matches!(violations[0], ValidationError::LockTime { .. }));
+
+        // The sequence locks are only enforced from the CSV activation height of the network
+        let height = 1_000;
+        utxos[0].creation_height = height - 9;
+        utxos[2].creation_height = 0;
+        let mut block = block;
+        block.txdata[1].lock_time = LockTime::ZERO;
+        let violations = check_input_locks(&block, height, mtp, &utxos, &params);
+        assert!(violations.is_empty());
+        let regtest = ChainParams::from(Network::Regtest);
+        let violations = check_input_locks(&block, height, mtp, &utxos, &regtest);
+        assert!(matches!(
+            violations[..],
+            [
+                ValidationError::SequenceLock { vin: 0, .. },
+                ValidationError::SequenceLock { vin: 1, .. },
+            ]
+        ));
+    }
+}